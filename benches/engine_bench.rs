@@ -1,4 +1,4 @@
-use criterion::{black_box, criterion_group, criterion_main, Criterion, BatchSize};
+use criterion::{criterion_group, criterion_main, Criterion, BatchSize};
 use kvs::{KvStore, KvsEngine, Sled};
 use tempfile::TempDir;
 use rand::{distributions::Alphanumeric, Rng};
@@ -7,19 +7,19 @@ use std::collections::HashMap;
 pub fn set_benchmark(c: &mut Criterion) {
     let mut group = c.benchmark_group("set_bench");
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path()).expect("failed to create kv store");
+    let store = KvStore::open(temp_dir.path()).expect("failed to create kv store");
     let kv = rand_key_values();
     group.bench_function("kvs", |b| b.iter_batched(||{},|_|{
         for (k,v) in kv.iter() {
-            store.set(k.to_string(), v.to_string()).unwrap();
+            store.set(k.as_bytes().to_vec(), v.as_bytes().to_vec()).unwrap();
         }
     },BatchSize::SmallInput));
 
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = Sled::open(temp_dir.path()).expect("failed to create kv store");
+    let store = Sled::open(temp_dir.path()).expect("failed to create kv store");
     group.bench_function("sled", |b| b.iter_batched(||{},|_|{
         for (k,v) in kv.iter() {
-            store.set(k.to_string(), v.to_string()).unwrap();
+            store.set(k.as_bytes().to_vec(), v.as_bytes().to_vec()).unwrap();
         }
     },BatchSize::SmallInput));
     group.finish();
@@ -28,25 +28,25 @@ pub fn set_benchmark(c: &mut Criterion) {
 pub fn get_benchmark(c: &mut Criterion) {
     let mut group = c.benchmark_group("get_bench");
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path()).expect("failed to create kv store");
+    let store = KvStore::open(temp_dir.path()).expect("failed to create kv store");
     let kv = rand_key_values();
     for (k,v) in kv.iter() {
-        store.set(k.to_string(), v.to_string()).unwrap();
+        store.set(k.as_bytes().to_vec(), v.as_bytes().to_vec()).unwrap();
     }
     group.bench_function("kvs", |b| b.iter_batched(||{},|_|{
         for (k,_) in kv.iter() {
-            store.get(k.to_string()).unwrap();
+            store.get(k.as_bytes()).unwrap();
         }
     },BatchSize::SmallInput));
 
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = Sled::open(temp_dir.path()).expect("failed to create kv store");
+    let store = Sled::open(temp_dir.path()).expect("failed to create kv store");
     for (k,v) in kv.iter() {
-        store.set(k.to_string(), v.to_string()).unwrap();
+        store.set(k.as_bytes().to_vec(), v.as_bytes().to_vec()).unwrap();
     }
     group.bench_function("sled", |b| b.iter_batched(||{},|_|{
         for (k,v) in kv.iter() {
-            store.set(k.to_string(), v.to_string()).unwrap();
+            store.set(k.as_bytes().to_vec(), v.as_bytes().to_vec()).unwrap();
         }
     },BatchSize::SmallInput));
     group.finish();
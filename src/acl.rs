@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::{KvsError, Result};
+
+// What a request does to a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Operation {
+    Read,
+    Write,
+    Delete,
+}
+
+impl Operation {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Operation::Read => "read",
+            Operation::Write => "write",
+            Operation::Delete => "delete",
+        }
+    }
+}
+
+// Which operations each user may perform on which keys. Rules are written one
+// per line as
+//
+//   <user> <operations> <key prefix>
+//
+// where operations is a comma-separated list of `read`, `write`, `delete` and
+// `admin`, which allows everything, and the prefix may end in `*`, so that `*`
+// alone covers every key. A user may perform an operation on a key if any of
+// their rules allows it; users without rules may do nothing. Blank lines and
+// lines starting with `#` are ignored.
+#[derive(Debug, Default)]
+pub struct Policy {
+    rules: HashMap<String, Vec<Rule>>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    prefix: Vec<u8>,
+    read: bool,
+    write: bool,
+    delete: bool,
+}
+
+impl Policy {
+    pub fn from_file(path: &Path) -> Result<Policy> {
+        let mut policy = Policy::default();
+        policy.add_rules(&fs::read_to_string(path)?)?;
+        Ok(policy)
+    }
+
+    pub fn add_rules(&mut self, rules: &str) -> Result<()> {
+        let lines = rules.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'));
+        for line in lines {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (user, ops, prefix) = match fields[..] {
+                [user, ops, prefix] => (user, ops, prefix),
+                _ => return Err(policy_error(line, "expected a user, operations and a key prefix")),
+            };
+            let mut rule = Rule{
+                prefix: prefix.strip_suffix('*').unwrap_or(prefix).as_bytes().to_vec(),
+                read: false,
+                write: false,
+                delete: false,
+            };
+            for op in ops.split(',') {
+                match op {
+                    "read" => rule.read = true,
+                    "write" => rule.write = true,
+                    "delete" => rule.delete = true,
+                    "admin" => {
+                        rule.read = true;
+                        rule.write = true;
+                        rule.delete = true;
+                    },
+                    _ => return Err(policy_error(line, &format!("unknown operation {:?}", op))),
+                }
+            }
+            self.rules.entry(user.to_string()).or_default().push(rule);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn allows(&self, user: &str, op: Operation, key: &[u8]) -> bool {
+        let rules = match self.rules.get(user) {
+            Some(rules) => rules,
+            None => return false,
+        };
+        rules.iter()
+            .filter(|rule| key.starts_with(&rule.prefix))
+            .any(|rule| match op {
+                Operation::Read => rule.read,
+                Operation::Write => rule.write,
+                Operation::Delete => rule.delete,
+            })
+    }
+}
+
+fn policy_error(line: &str, msg: &str) -> KvsError {
+    KvsError::StringError(format!("invalid access rule {:?}: {}", line, msg))
+}
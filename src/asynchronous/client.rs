@@ -0,0 +1,75 @@
+use tokio::io::BufReader;
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+use crate::client::{connection_closed, handshake_result, response_result, unexpected_reply};
+use crate::protocol;
+use crate::{Hello, Reply, Req, Request, Result};
+
+// The async counterpart of `KvsClient`, speaking the same protocol over a
+// tokio `TcpStream`.
+pub struct AsyncKvsClient {
+    stream: BufReader<TcpStream>,
+    agreed: Hello,
+    next_id: u64,
+}
+
+impl AsyncKvsClient {
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<AsyncKvsClient> {
+        let mut stream = BufReader::new(TcpStream::connect(addr).await?);
+        protocol::send_async(&mut stream, &Hello::local()).await?;
+        let agreed = match protocol::receive_async(&mut stream).await? {
+            Some(resp) => handshake_result(resp)?,
+            None => return Err(connection_closed()),
+        };
+        Ok(AsyncKvsClient{stream, agreed, next_id: 1})
+    }
+
+    pub fn protocol_version(&self) -> u32 {
+        self.agreed.version
+    }
+
+    pub fn capabilities(&self) -> &[String] {
+        &self.agreed.capabilities
+    }
+
+    // Presents `token` to a server that requires authentication, failing
+    // with `KvsError::Unauthorized` if it is not accepted.
+    pub async fn authenticate(&mut self, token: &str) -> Result<()> {
+        match self.request(Req::Auth{token: token.to_string()}).await? {
+            Reply::Done => Ok(()),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    pub async fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.request(Req::Get{key: key.to_vec()}).await? {
+            Reply::Value(value) => Ok(value),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    pub async fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        match self.request(Req::Set{key, value}).await? {
+            Reply::Done => Ok(()),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    pub async fn remove(&mut self, key: &[u8]) -> Result<()> {
+        match self.request(Req::Remove{key: key.to_vec()}).await? {
+            Reply::Done => Ok(()),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    async fn request(&mut self, req: Req) -> Result<Reply> {
+        req.check_version(self.agreed.version)?;
+        let id = self.next_id;
+        self.next_id += 1;
+        protocol::send_async(&mut self.stream, &Request{id, req}).await?;
+        match protocol::receive_async(&mut self.stream).await? {
+            Some(resp) => response_result(resp, id),
+            None => Err(connection_closed()),
+        }
+    }
+}
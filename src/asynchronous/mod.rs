@@ -0,0 +1,4 @@
+mod server;
+pub use self::server::AsyncKvsServer;
+mod client;
+pub use self::client::AsyncKvsClient;
@@ -0,0 +1,160 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use log::{debug, error, info};
+use serde::Serialize;
+use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::protocol::{self, ErrorCode, Hello, HelloResponse, Reply, Req, Request, Response, ResponseError};
+use crate::acl::Policy;
+use crate::auth::{Authenticator, Session};
+use crate::server::{authorize, execute, response_error};
+use crate::{KvsEngine, KvsError, Result};
+
+// Serves the same protocol as `KvsServer` from a tokio runtime, with a task
+// rather than a thread per connection. Engine calls block, so they run on
+// tokio's blocking thread pool.
+pub struct AsyncKvsServer<E: KvsEngine> {
+    engine: E,
+    auth: Option<Arc<Authenticator>>,
+    policy: Option<Arc<Policy>>,
+}
+
+impl<E: KvsEngine> AsyncKvsServer<E> {
+    pub fn new(engine: E) -> Self {
+        AsyncKvsServer{engine, auth: None, policy: None}
+    }
+
+    // Requires clients to present one of `auth`'s tokens before making
+    // requests, as `KvsServer::with_auth` does.
+    pub fn with_auth(mut self, auth: Arc<Authenticator>) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    // Limits what each authenticated user may do to which keys. Requires
+    // `with_auth`.
+    pub fn with_policy(mut self, policy: Arc<Policy>) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    pub async fn run(self, addr: SocketAddr) -> Result<()> {
+        self.run_until(addr, std::future::pending()).await
+    }
+
+    // Serves clients until `shutdown` completes, then stops accepting
+    // connections and flushes the engine.
+    pub async fn run_until<F: Future<Output = ()>>(self, addr: SocketAddr, shutdown: F) -> Result<()> {
+        if self.policy.is_some() && self.auth.is_none() {
+            return Err(KvsError::StringError("an access policy needs authentication to identify users".to_string()));
+        }
+        let listener = TcpListener::bind(addr).await?;
+        tokio::pin!(shutdown);
+        loop {
+            let (stream, peer) = tokio::select! {
+                _ = &mut shutdown => break,
+                res = listener.accept() => match res {
+                    Ok(accepted) => accepted,
+                    Err(error) => {
+                        error!("error accepting connection: {}", error);
+                        continue;
+                    }
+                },
+            };
+            debug!("accepted connection from {}", peer);
+            let engine = self.engine.clone();
+            let session = Session::new(self.auth.clone(), self.policy.clone());
+            tokio::spawn(async move {
+                if let Err(e) = serve(engine, stream, session).await {
+                    error!("error serving client {}: {}", peer, e);
+                }
+            });
+        }
+        let engine = self.engine.clone();
+        blocking(move || engine.flush()).await?;
+        info!("server stopped");
+        Ok(())
+    }
+}
+
+async fn serve<E: KvsEngine>(engine: E, stream: TcpStream, mut session: Session) -> Result<()> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    let hello = match protocol::receive_async::<Hello, _>(&mut reader).await {
+        Ok(Some(hello)) => hello,
+        Ok(None) => return Ok(()),
+        Err(error) => {
+            if protocol::is_invalid_input(&error) {
+                let _ = send_resp(&mut writer, HelloResponse::Err(format!("invalid handshake: {}", error))).await;
+            }
+            return Err(error);
+        },
+    };
+    let version = match Hello::local().negotiate(&hello) {
+        Ok(agreed) => {
+            let version = agreed.version;
+            send_resp(&mut writer, HelloResponse::Ok(agreed)).await?;
+            version
+        },
+        Err(msg) => {
+            send_resp(&mut writer, HelloResponse::Err(msg.clone())).await?;
+            return Err(KvsError::Handshake(msg));
+        },
+    };
+    loop {
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+        let request = match protocol::receive_async::<Request, _>(&mut reader).await {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(error) => {
+                if protocol::is_invalid_input(&error) {
+                    let result = Err(ResponseError{
+                        code: ErrorCode::InvalidRequest,
+                        message: format!("invalid request: {}", error),
+                    });
+                    let _ = send_resp(&mut writer, Response{id: 0, result}).await;
+                }
+                return Err(error);
+            },
+        };
+        let req = request.req;
+        let result = match req.check_version(version) {
+            Ok(()) => match req {
+                Req::Auth { token } => session.login(&token).map(|()| Reply::Done),
+                req => match authorize(&session, &req) {
+                    Ok(()) => {
+                        let engine = engine.clone();
+                        let session = session.clone();
+                        blocking(move || execute(&engine, &session, req)).await
+                    },
+                    Err(error) => Err(error),
+                },
+            },
+            Err(error) => Err(error),
+        };
+        let result = result.map_err(|error| response_error(error, version));
+        protocol::queue_async(&mut writer, &Response{id: request.id, result}).await?;
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+async fn blocking<T, F>(f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| KvsError::StringError(e.to_string()))?
+}
+
+async fn send_resp<T: Serialize>(writer: &mut BufWriter<OwnedWriteHalf>, resp: T) -> Result<()> {
+    protocol::send_async(writer, &resp).await
+}
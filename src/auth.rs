@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::acl::{Operation, Policy};
+use crate::{KvsError, Result};
+
+// The tokens a server accepts and the users they belong to. Tokens are
+// configured as `user:token` entries, one per line or separated by commas;
+// blank lines and lines starting with `#` are ignored.
+#[derive(Debug, Default)]
+pub struct Authenticator {
+    users: HashMap<String, String>,
+}
+
+impl Authenticator {
+    pub fn from_file(path: &Path) -> Result<Authenticator> {
+        let mut auth = Authenticator::default();
+        auth.add_entries(&fs::read_to_string(path)?)?;
+        Ok(auth)
+    }
+
+    pub fn add_entries(&mut self, entries: &str) -> Result<()> {
+        let entries = entries.lines()
+            .filter(|line| !line.trim_start().starts_with('#'))
+            .flat_map(|line| line.split(','))
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty());
+        for entry in entries {
+            match entry.split_once(':') {
+                Some((user, token)) if !user.is_empty() && !token.is_empty() => {
+                    self.users.insert(token.to_string(), user.to_string());
+                },
+                _ => return Err(KvsError::StringError("auth entries must look like user:token".to_string())),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    // Returns the user `token` belongs to. Every configured token is compared
+    // in full, so the time taken does not hint at how close a guess was.
+    pub(crate) fn authenticate(&self, token: &str) -> Option<&str> {
+        let mut found = None;
+        for (candidate, user) in &self.users {
+            if constant_time_eq(candidate.as_bytes(), token.as_bytes()) {
+                found = Some(user.as_str());
+            }
+        }
+        found
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// The authentication state of one connection. Without an `Authenticator`
+// every request is allowed and any token is accepted. With a `Policy` as
+// well, the user is only allowed what the policy grants them.
+#[derive(Clone)]
+pub(crate) struct Session {
+    auth: Option<Arc<Authenticator>>,
+    policy: Option<Arc<Policy>>,
+    user: Option<String>,
+}
+
+impl Session {
+    pub(crate) fn new(auth: Option<Arc<Authenticator>>, policy: Option<Arc<Policy>>) -> Session {
+        Session{auth, policy, user: None}
+    }
+
+    pub(crate) fn login(&mut self, token: &str) -> Result<()> {
+        self.login_as(None, token)
+    }
+
+    // Logs in with `token`, which must also belong to `user` if given. A
+    // failed attempt logs out whoever was logged in before.
+    pub(crate) fn login_as(&mut self, user: Option<&str>, token: &str) -> Result<()> {
+        let auth = match &self.auth {
+            Some(auth) => auth,
+            None => return Ok(()),
+        };
+        self.user = None;
+        match auth.authenticate(token) {
+            Some(found) if user.is_none_or(|user| user == found) => {
+                self.user = Some(found.to_string());
+                Ok(())
+            },
+            _ => Err(KvsError::Unauthorized("invalid token".to_string())),
+        }
+    }
+
+    // Fails unless requests may be made.
+    pub(crate) fn check(&self) -> Result<()> {
+        if self.auth.is_some() && self.user.is_none() {
+            return Err(KvsError::Unauthorized("authentication required".to_string()));
+        }
+        Ok(())
+    }
+
+    // Fails unless `op` may be performed on `key`.
+    pub(crate) fn authorize(&self, op: Operation, key: &[u8]) -> Result<()> {
+        self.check()?;
+        match (&self.policy, &self.user) {
+            (Some(policy), Some(user)) if !policy.allows(user, op, key) => Err(KvsError::Forbidden(format!(
+                "{} may not {} {:?}",
+                user,
+                op.name(),
+                String::from_utf8_lossy(key)
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
@@ -1,16 +1,64 @@
-use kvs::{GetResponse, KvStore, KvsError, RemoveResponse, Req, Result, SetResponse};
-use serde::Deserialize;
-use std::env::current_dir;
-use std::ops::Rem;
+use kvs::{tls, KvsClient, Result};
 use std::process::exit;
 use std::net::SocketAddr;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
-use std::net::{TcpStream};
-use std::io::{self, Read, Write, BufReader};
-use serde_json::Deserializer;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::time::Duration;
+use base64::{engine::general_purpose::STANDARD, Engine};
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const ADDRESS_FORMAT: &str = "IP:PORT";
+const ENCODING_FORMAT: &str = "raw|base64|hex";
+
+// How keys and values given on the command line, and those printed by `get`
+// and `scan`, are encoded.
+#[derive(Debug, Clone, Copy)]
+enum Encoding {
+    Raw,
+    Base64,
+    Hex,
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Encoding, String> {
+        match s {
+            "raw" => Ok(Encoding::Raw),
+            "base64" => Ok(Encoding::Base64),
+            "hex" => Ok(Encoding::Hex),
+            _ => Err(format!("unknown encoding: {}", s)),
+        }
+    }
+}
+
+impl Encoding {
+    fn decode(self, s: &str) -> Vec<u8> {
+        let res = match self {
+            Encoding::Raw => Ok(s.as_bytes().to_vec()),
+            Encoding::Base64 => STANDARD.decode(s).map_err(|e| e.to_string()),
+            Encoding::Hex => hex::decode(s).map_err(|e| e.to_string()),
+        };
+        match res {
+            Ok(bytes) => bytes,
+            Err(error) => {
+                eprintln!("invalid {:?} input {:?}: {}", self, s, error);
+                exit(1);
+            }
+        }
+    }
+
+    fn encode(self, bytes: Vec<u8>) -> Vec<u8> {
+        match self {
+            Encoding::Raw => bytes,
+            Encoding::Base64 => STANDARD.encode(bytes).into_bytes(),
+            Encoding::Hex => hex::encode(bytes).into_bytes(),
+        }
+    }
+}
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "kvs-client", author, about)]
@@ -24,80 +72,242 @@ enum Commands {
         key: String,
         value: String,
         #[structopt(
-            long="addr", 
-            value_name = ADDRESS_FORMAT, 
-            default_value = DEFAULT_LISTENING_ADDRESS,
-            parse(try_from_str)
+            long="ttl",
+            value_name = "SECONDS",
+            help = "Remove the key once this many seconds have passed",
+        )]
+        ttl: Option<u64>,
+        #[structopt(flatten)]
+        conn: ConnectOpts,
+        #[structopt(
+            long="encoding",
+            value_name = ENCODING_FORMAT,
+            default_value = "raw",
         )]
-        addr: SocketAddr
+        encoding: Encoding,
     } ,
     Get {key: String,
+        #[structopt(flatten)]
+        conn: ConnectOpts,
         #[structopt(
-            long="addr", 
-            value_name = ADDRESS_FORMAT, 
-            default_value = DEFAULT_LISTENING_ADDRESS,
-            parse(try_from_str)
+            long="encoding",
+            value_name = ENCODING_FORMAT,
+            default_value = "raw",
         )]
-        addr: SocketAddr
+        encoding: Encoding,
     },
     Rm {key: String,
+        #[structopt(flatten)]
+        conn: ConnectOpts,
+        #[structopt(
+            long="encoding",
+            value_name = ENCODING_FORMAT,
+            default_value = "raw",
+        )]
+        encoding: Encoding,
+    },
+    // Lists keys and values in key order, one tab separated pair per line.
+    Scan {
+        #[structopt(
+            long="prefix",
+            value_name = "PREFIX",
+            help = "Only list keys starting with this",
+        )]
+        prefix: Option<String>,
+        #[structopt(
+            long="start",
+            value_name = "KEY",
+            help = "List keys from this one on, such as the next key printed by an earlier scan",
+        )]
+        start: Option<String>,
+        #[structopt(
+            long="end",
+            value_name = "KEY",
+            help = "List keys before this one",
+        )]
+        end: Option<String>,
+        #[structopt(
+            long="limit",
+            value_name = "N",
+            help = "List at most this many keys, printing the next one to stderr if there are more",
+        )]
+        limit: Option<usize>,
+        #[structopt(flatten)]
+        conn: ConnectOpts,
         #[structopt(
-            long="addr", 
-            value_name = ADDRESS_FORMAT, 
-            default_value = DEFAULT_LISTENING_ADDRESS,
-            parse(try_from_str)
+            long="encoding",
+            value_name = ENCODING_FORMAT,
+            default_value = "raw",
         )]
-        addr: SocketAddr
+        encoding: Encoding,
     },
 }
 
+// Where and how to reach the server, shared by every command.
+#[derive(StructOpt, Debug)]
+struct ConnectOpts {
+    #[structopt(
+        long="addr", 
+        value_name = ADDRESS_FORMAT, 
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+    )]
+    addr: SocketAddr,
+    #[structopt(
+        long="unix-socket",
+        value_name = "PATH",
+        help = "Connect to a Unix domain socket instead of --addr",
+        parse(from_os_str)
+    )]
+    unix_socket: Option<PathBuf>,
+    #[structopt(
+        long="tls-ca",
+        value_name = "PEM-FILE",
+        help = "Connect over TLS, trusting the CA certificates in this file",
+        parse(from_os_str)
+    )]
+    tls_ca: Option<PathBuf>,
+    #[structopt(
+        long="tls-cert",
+        value_name = "PEM-FILE",
+        help = "Client certificate chain to present over TLS",
+        requires_all = &["tls-key", "tls-ca"],
+        parse(from_os_str)
+    )]
+    tls_cert: Option<PathBuf>,
+    #[structopt(
+        long="tls-key",
+        value_name = "PEM-FILE",
+        help = "Private key for --tls-cert",
+        requires_all = &["tls-cert", "tls-ca"],
+        parse(from_os_str)
+    )]
+    tls_key: Option<PathBuf>,
+    #[structopt(
+        long="tls-server-name",
+        value_name = "NAME",
+        requires = "tls-ca",
+        help = "Name the server's certificate must match [default: the IP of --addr]",
+    )]
+    tls_server_name: Option<String>,
+    #[structopt(
+        long="token",
+        value_name = "TOKEN",
+        env = "KVS_TOKEN",
+        hide_env_values = true,
+        help = "Token to authenticate with, for servers that require one",
+    )]
+    token: Option<String>,
+}
+
 fn main() -> Result<()> {
 
     let cli = Args::from_args();
     match &cli.command {
-        Commands::Set {key, value ,addr} => {
-            let stream = TcpStream::connect(addr)?;
-            serde_json::to_writer(&stream ,&Req::Set{key: key.to_string(),value: value.to_string()})?;
-            let mut br = Deserializer::from_reader(BufReader::new(&stream));  
-            let resp = SetResponse::deserialize(&mut br);
-            //println!("{:?}", resp);
+        Commands::Set {key, value, ttl, conn, encoding} => {
+            let mut client = connect(conn)?;
+            let (key, value) = (encoding.decode(key), encoding.decode(value));
+            let res = match ttl {
+                Some(secs) => client.set_with_ttl(key, value, Duration::from_secs(*secs)),
+                None => client.set(key, value),
+            };
+            if let Err(error) = res {
+                eprint!("{}", error);
+                exit(1);
+            }
             Ok(())
         }
-        Commands::Get {key, addr} => {
-            let stream = TcpStream::connect(addr)?;
-            serde_json::to_writer(&stream ,&Req::Get{key: key.to_string()})?;
-            let mut br = Deserializer::from_reader(BufReader::new(&stream));  
-            let resp = GetResponse::deserialize(&mut br)?;
-            match resp {
-                GetResponse::Ok(x) => {
-                    match x {
-                        Some(s) => {
-                            println!("{}", s);
-                        },
-                        None => {
-                            println!("{}", "Key not found");
-                        }
-                    }
+        Commands::Get {key, conn, encoding} => {
+            let mut client = connect(conn)?;
+            match client.get(&encoding.decode(key)) {
+                Ok(Some(value)) => {
+                    let mut out = io::stdout().lock();
+                    out.write_all(&encoding.encode(value))?;
+                    writeln!(out)?;
+                },
+                Ok(None) => {
+                    println!("Key not found");
                 },
-                GetResponse::Err(error) => {
-                    panic!("{}",error);
+                Err(error) => {
+                    eprint!("{}", error);
+                    exit(1);
                 }
             }
             Ok(())
         }
-        Commands::Rm {key, addr} => {
-            let stream = TcpStream::connect(addr)?;
-            serde_json::to_writer(&stream ,&Req::Remove{key: key.to_string()})?;
-            let mut br = Deserializer::from_reader(BufReader::new(&stream));  
-            let resp = RemoveResponse::deserialize(&mut br)?;
-            match resp {
-                RemoveResponse::Ok(()) => {},
-                RemoveResponse::Err(error) => {
-                    eprint!("{}", error);
-                    exit(1);
+        Commands::Rm {key, conn, encoding} => {
+            let mut client = connect(conn)?;
+            if let Err(error) = client.remove(&encoding.decode(key)) {
+                eprint!("{}", error);
+                exit(1);
+            }
+            Ok(())
+        }
+        Commands::Scan {prefix, start, end, limit, conn, encoding} => {
+            let mut client = connect(conn)?;
+            let prefix = prefix.as_deref().map(|p| encoding.decode(p)).unwrap_or_default();
+            let start = start.as_deref().map_or(Bound::Unbounded, |k| Bound::Included(encoding.decode(k)));
+            let end = end.as_deref().map_or(Bound::Unbounded, |k| Bound::Excluded(encoding.decode(k)));
+            let mut out = io::stdout().lock();
+            for (n, entry) in client.scan_prefix_range(&prefix, (start, end)).enumerate() {
+                let (key, value) = match entry {
+                    Ok(entry) => entry,
+                    Err(error) => {
+                        eprint!("{}", error);
+                        exit(1);
+                    }
+                };
+                if Some(n) == *limit {
+                    out.flush()?;
+                    let mut err = io::stderr().lock();
+                    err.write_all(b"next: ")?;
+                    err.write_all(&encoding.encode(key))?;
+                    writeln!(err)?;
+                    break;
                 }
+                out.write_all(&encoding.encode(key))?;
+                out.write_all(b"\t")?;
+                out.write_all(&encoding.encode(value))?;
+                writeln!(out)?;
             }
             Ok(())
         }
     }
-}
\ No newline at end of file
+}
+
+fn connect(opts: &ConnectOpts) -> Result<KvsClient> {
+    let mut client = open(opts)?;
+    if let Some(token) = &opts.token {
+        client.authenticate(token)?;
+    }
+    Ok(client)
+}
+
+fn open(opts: &ConnectOpts) -> Result<KvsClient> {
+    if let Some(path) = &opts.unix_socket {
+        return connect_unix(path);
+    }
+    match &opts.tls_ca {
+        Some(ca) => {
+            let identity = opts.tls_cert.as_deref().zip(opts.tls_key.as_deref());
+            let config = tls::client_config(ca, identity)?;
+            let server_name = match &opts.tls_server_name {
+                Some(name) => name.clone(),
+                None => opts.addr.ip().to_string(),
+            };
+            KvsClient::connect_tls(opts.addr, &server_name, config)
+        },
+        None => KvsClient::connect(opts.addr),
+    }
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &Path) -> Result<KvsClient> {
+    KvsClient::connect_unix(path)
+}
+
+#[cfg(not(unix))]
+fn connect_unix(_: &Path) -> Result<KvsClient> {
+    eprintln!("--unix-socket is only supported on Unix");
+    exit(1);
+}
@@ -1,12 +1,19 @@
-use kvs::{KvStore, Result, KvsServer, KvsEngine,Sled};
-use std::env::current_dir;
+use kvs::{tls, AsyncKvsServer, Authenticator, Policy, KvStore, KvsError, Result, KvsServer, KvsEngine, Sled};
+use kvs::thread_pool::{ThreadPool, NaiveThreadPool, SharedQueueThreadPool, RayonThreadPool};
+use std::env::{self, current_dir};
 use std::process::exit;
 use std::net::SocketAddr;
+use std::num::ParseIntError;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
 use structopt::StructOpt;
 use log::*;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const ADDRESS_FORMAT: &str = "IP:PORT";
+// Holds `user:token` entries, as an alternative or addition to --auth-file.
+const AUTH_TOKENS_VAR: &str = "KVS_AUTH_TOKENS";
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "kvs-server", author, about)]
@@ -14,41 +21,253 @@ struct Args {
     #[structopt(
         long="addr", 
         value_name = ADDRESS_FORMAT, 
-        default_value = DEFAULT_LISTENING_ADDRESS,
+        help = "Address to serve on [default: 127.0.0.1:4000, or none with --unix-socket]",
         parse(try_from_str)
     )]
-    addr: SocketAddr,
+    addr: Option<SocketAddr>,
+    #[structopt(
+        long="unix-socket",
+        value_name = "PATH",
+        help = "Also serve on a Unix domain socket at this path",
+        parse(from_os_str)
+    )]
+    unix_socket: Option<PathBuf>,
+    #[structopt(
+        long="unix-socket-mode",
+        value_name = "OCTAL",
+        default_value = "600",
+        help = "File mode of the Unix domain socket, which controls who may connect",
+        parse(try_from_str = parse_mode)
+    )]
+    unix_socket_mode: u32,
     #[structopt(
         long="engine", 
         value_name = "ENGINE-NAME", 
         default_value = "kvs",
     )]
     engine: String,
+    #[structopt(
+        long="pool",
+        value_name = "naive|shared-queue|rayon",
+        default_value = "shared-queue",
+    )]
+    pool: String,
+    #[structopt(
+        long="threads",
+        value_name = "N",
+        parse(try_from_str = parse_threads)
+    )]
+    threads: Option<u32>,
+    #[structopt(
+        long="tls-cert",
+        value_name = "PEM-FILE",
+        help = "Serve --addr over TLS with the certificate chain in this file",
+        requires = "tls-key",
+        parse(from_os_str)
+    )]
+    tls_cert: Option<PathBuf>,
+    #[structopt(
+        long="tls-key",
+        value_name = "PEM-FILE",
+        help = "Private key for --tls-cert",
+        requires = "tls-cert",
+        parse(from_os_str)
+    )]
+    tls_key: Option<PathBuf>,
+    #[structopt(
+        long="tls-client-ca",
+        value_name = "PEM-FILE",
+        help = "Require TLS clients to present a certificate signed by a CA in this file",
+        requires = "tls-cert",
+        parse(from_os_str)
+    )]
+    tls_client_ca: Option<PathBuf>,
+    #[structopt(
+        long="resp-addr",
+        value_name = ADDRESS_FORMAT,
+        help = "Also serve the Redis protocol (RESP2) on this address",
+        parse(try_from_str)
+    )]
+    resp_addr: Option<SocketAddr>,
+    #[structopt(
+        long="http-addr",
+        value_name = ADDRESS_FORMAT,
+        help = "Also serve the HTTP API on this address",
+        parse(try_from_str)
+    )]
+    http_addr: Option<SocketAddr>,
+    #[structopt(
+        long="auth-file",
+        value_name = "FILE",
+        help = "Require clients to authenticate with a token from this file of user:token lines \
+                (tokens can also be given in KVS_AUTH_TOKENS)",
+        parse(from_os_str)
+    )]
+    auth_file: Option<PathBuf>,
+    #[structopt(
+        long="acl-file",
+        value_name = "FILE",
+        help = "Limit each user to the operations on key prefixes listed in this file",
+        parse(from_os_str)
+    )]
+    acl_file: Option<PathBuf>,
+    #[structopt(
+        long="async",
+        help = "Serve connections from a tokio runtime instead of a thread pool",
+    )]
+    use_async: bool,
 }
 
 fn main() -> Result<()> {
-    stderrlog::new().module(module_path!()).verbosity(10).init().unwrap();
+    stderrlog::new().module(module_path!()).module("kvs").verbosity(10).init().unwrap();
     let temp_dir = current_dir()?;
     let cli = Args::from_args();
     info!("server version: {}", env!("CARGO_PKG_VERSION"));
-    info!("listening on: {}", cli.addr);
     info!("using engine: {}", cli.engine);
     match cli.engine.as_str() {
         "kvs" => {
             let kv = KvStore::open(temp_dir.as_path()).expect("failed to open kv");
-            let kvs = KvsServer::new(kv);
-            kvs.run(cli.addr)?;
-            Ok(())
+            run_with_pool(kv, &cli)
         },
         "sled" => {
            let sl = Sled::open(temp_dir.as_path()).expect("failed to open sled");
-            let kvs = KvsServer::new(sl);
-            kvs.run(cli.addr)?;
-           Ok(())
+           run_with_pool(sl, &cli)
         },
         _ => {
             eprintln!("unsupported engine");
             exit(1);
         }
     }
-}
\ No newline at end of file
+}
+
+fn run_with_pool<E: KvsEngine>(engine: E, cli: &Args) -> Result<()> {
+    let threads = match cli.threads {
+        Some(threads) => threads,
+        None => thread::available_parallelism().map(|n| n.get() as u32).unwrap_or(4),
+    };
+    let auth = authenticator(cli)?;
+    if cli.use_async {
+        if cli.resp_addr.is_some() || cli.http_addr.is_some() || cli.unix_socket.is_some() || cli.tls_cert.is_some() {
+            eprintln!("--resp-addr, --http-addr, --unix-socket and TLS are not supported with --async");
+            exit(1);
+        }
+        info!("using async runtime with {} threads", threads);
+        return run_async(engine, threads as usize, cli, auth);
+    }
+    info!("using pool: {} with {} threads", cli.pool, threads);
+    match cli.pool.as_str() {
+        "naive" => run(engine, NaiveThreadPool::new(threads)?, cli, auth),
+        "shared-queue" => run(engine, SharedQueueThreadPool::new(threads)?, cli, auth),
+        "rayon" => run(engine, RayonThreadPool::new(threads)?, cli, auth),
+        _ => {
+            eprintln!("unsupported pool");
+            exit(1);
+        }
+    }
+}
+
+fn run<E: KvsEngine, P: ThreadPool>(engine: E, pool: P, cli: &Args, auth: Option<Arc<Authenticator>>) -> Result<()> {
+    let mut server = KvsServer::new(engine, pool);
+    if let Some(auth) = auth {
+        server = server.with_auth(auth);
+    }
+    if let Some(path) = &cli.acl_file {
+        server = server.with_policy(Arc::new(Policy::from_file(path)?));
+    }
+    if let (Some(cert), Some(key)) = (&cli.tls_cert, &cli.tls_key) {
+        server = server.with_tls(tls::server_config(cert, key, cli.tls_client_ca.as_deref())?);
+    }
+    if let Some(resp_addr) = cli.resp_addr {
+        server = server.with_resp(resp_addr);
+    }
+    if let Some(http_addr) = cli.http_addr {
+        server = server.with_http(http_addr);
+    }
+    if let Some(path) = &cli.unix_socket {
+        server = with_unix_socket(server, path, cli.unix_socket_mode);
+    }
+    let handle = server.shutdown_handle();
+    ctrlc::set_handler(move || {
+        info!("received termination signal");
+        handle.shutdown();
+    }).map_err(|e| KvsError::StringError(e.to_string()))?;
+    match tcp_addr(cli) {
+        Some(addr) => server.run(addr),
+        None => server.serve(),
+    }
+}
+
+// Authentication is required if tokens are configured in a file, the
+// environment, or both.
+fn authenticator(cli: &Args) -> Result<Option<Arc<Authenticator>>> {
+    let mut auth = match &cli.auth_file {
+        Some(path) => Authenticator::from_file(path)?,
+        None => Authenticator::default(),
+    };
+    if let Ok(tokens) = env::var(AUTH_TOKENS_VAR) {
+        auth.add_entries(&tokens)?;
+    }
+    if auth.is_empty() {
+        if cli.auth_file.is_some() {
+            return Err(KvsError::StringError("the auth file has no tokens".to_string()));
+        }
+        return Ok(None);
+    }
+    info!("requiring authentication");
+    Ok(Some(Arc::new(auth)))
+}
+
+// The default address is only used when there is no Unix socket to serve on
+// instead.
+fn tcp_addr(cli: &Args) -> Option<SocketAddr> {
+    match (cli.addr, &cli.unix_socket) {
+        (Some(addr), _) => Some(addr),
+        (None, None) => Some(DEFAULT_LISTENING_ADDRESS.parse().unwrap()),
+        (None, Some(_)) => None,
+    }
+}
+
+#[cfg(unix)]
+fn with_unix_socket<E: KvsEngine, P: ThreadPool>(server: KvsServer<E, P>, path: &Path, mode: u32) -> KvsServer<E, P> {
+    server.with_unix_socket(path, mode)
+}
+
+#[cfg(not(unix))]
+fn with_unix_socket<E: KvsEngine, P: ThreadPool>(_: KvsServer<E, P>, _: &Path, _: u32) -> KvsServer<E, P> {
+    eprintln!("--unix-socket is only supported on Unix");
+    exit(1);
+}
+
+fn parse_mode(s: &str) -> std::result::Result<u32, ParseIntError> {
+    u32::from_str_radix(s, 8)
+}
+
+fn parse_threads(s: &str) -> std::result::Result<u32, String> {
+    match s.parse::<u32>() {
+        Ok(0) => Err("must be at least 1".to_owned()),
+        Ok(threads) => Ok(threads),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn run_async<E: KvsEngine>(engine: E, threads: usize, cli: &Args, auth: Option<Arc<Authenticator>>) -> Result<()> {
+    let mut server = AsyncKvsServer::new(engine);
+    if let Some(auth) = auth {
+        server = server.with_auth(auth);
+    }
+    if let Some(path) = &cli.acl_file {
+        server = server.with_policy(Arc::new(Policy::from_file(path)?));
+    }
+    let addr = tcp_addr(cli).expect("no Unix socket, so a TCP address");
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(threads)
+        .enable_io()
+        .build()?;
+    let shutdown = Arc::new(tokio::sync::Notify::new());
+    let signal = Arc::clone(&shutdown);
+    ctrlc::set_handler(move || {
+        info!("received termination signal");
+        signal.notify_one();
+    }).map_err(|e| KvsError::StringError(e.to_string()))?;
+    runtime.block_on(server.run_until(addr, shutdown.notified()))
+}
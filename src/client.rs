@@ -0,0 +1,298 @@
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::Path;
+use rustls::{ClientConfig, ClientConnection};
+use rustls_pki_types::ServerName;
+
+use crate::net::Stream;
+use crate::protocol;
+use crate::tls::tls_error;
+use crate::{Hello, HelloResponse, KeyValue, KvsError, Reply, Req, Request, Response, Result, WriteBatch};
+
+// How many pipelined requests may be waiting for their responses. Past
+// this, responses are read before more requests are sent, so that neither
+// side can block forever writing to a peer that is not reading.
+const PIPELINE_WINDOW: usize = 128;
+
+// How many entries a scan asks for at a time.
+const SCAN_PAGE_SIZE: u32 = 256;
+
+// A connection to a `KvsServer`. Requests are sent over the same connection,
+// either one at a time or several at once with `pipeline`.
+pub struct KvsClient {
+    reader: BufReader<Stream>,
+    writer: BufWriter<Stream>,
+    agreed: Hello,
+    next_id: u64,
+}
+
+impl KvsClient {
+    // Connects and performs the protocol handshake, failing with
+    // `KvsError::Handshake` if the server does not speak a compatible version.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<KvsClient> {
+        KvsClient::handshake(Stream::Tcp(TcpStream::connect(addr)?))
+    }
+
+    // Like `connect`, over TLS. The server's certificate must be valid for
+    // `server_name`, a DNS name or IP address. See `tls::client_config`.
+    pub fn connect_tls<A: ToSocketAddrs>(addr: A, server_name: &str, config: Arc<ClientConfig>) -> Result<KvsClient> {
+        let server_name = ServerName::try_from(server_name.to_string()).map_err(tls_error)?;
+        let conn = ClientConnection::new(config, server_name).map_err(tls_error)?;
+        KvsClient::handshake(Stream::tls(TcpStream::connect(addr)?, conn))
+    }
+
+    // Like `connect`, for a server listening on a Unix domain socket.
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> Result<KvsClient> {
+        KvsClient::handshake(Stream::Unix(UnixStream::connect(path)?))
+    }
+
+    fn handshake(stream: Stream) -> Result<KvsClient> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        protocol::send(&mut writer, &Hello::local())?;
+        let agreed = match protocol::receive(&mut reader)? {
+            Some(resp) => handshake_result(resp)?,
+            None => return Err(connection_closed()),
+        };
+        Ok(KvsClient{reader, writer, agreed, next_id: 1})
+    }
+
+    // The protocol version agreed on with the server.
+    pub fn protocol_version(&self) -> u32 {
+        self.agreed.version
+    }
+
+    // The optional features both the client and the server support.
+    pub fn capabilities(&self) -> &[String] {
+        &self.agreed.capabilities
+    }
+
+    // Presents `token` to a server that requires authentication, failing
+    // with `KvsError::Unauthorized` if it is not accepted.
+    pub fn authenticate(&mut self, token: &str) -> Result<()> {
+        match self.request(Req::Auth{token: token.to_string()})? {
+            Reply::Done => Ok(()),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.request(Req::Get{key: key.to_vec()})? {
+            Reply::Value(value) => Ok(value),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        match self.request(Req::Set{key, value})? {
+            Reply::Done => Ok(()),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    // Sets a key that expires once `ttl` has passed.
+    pub fn set_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let ttl_ms = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        match self.request(Req::SetWithTtl{key, value, ttl_ms})? {
+            Reply::Done => Ok(()),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Result<()> {
+        match self.request(Req::Remove{key: key.to_vec()})? {
+            Reply::Done => Ok(()),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    // Applies all of `batch`'s writes or none of them.
+    pub fn apply_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        match self.request(Req::Batch(batch.into()))? {
+            Reply::Done => Ok(()),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    // The keys in `range` and their values, in key order, fetched from the
+    // server a page at a time as the iterator advances.
+    pub fn scan<R: RangeBounds<Vec<u8>>>(&mut self, range: R) -> RemoteScan<'_> {
+        self.scan_prefix_range(b"", range)
+    }
+
+    // Like `scan`, for the keys starting with `prefix`.
+    pub fn scan_prefix(&mut self, prefix: &[u8]) -> RemoteScan<'_> {
+        self.scan_prefix_range(prefix, ..)
+    }
+
+    // Like `scan`, for the keys in `range` that start with `prefix`.
+    pub fn scan_prefix_range<R: RangeBounds<Vec<u8>>>(&mut self, prefix: &[u8], range: R) -> RemoteScan<'_> {
+        // the protocol's ranges include their start and exclude their end,
+        // and the key right after `k` is `k` followed by a zero byte
+        let start = match range.start_bound() {
+            Bound::Included(k) => Some(k.clone()),
+            Bound::Excluded(k) => Some(successor(k)),
+            Bound::Unbounded => None,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(k) => Some(successor(k)),
+            Bound::Excluded(k) => Some(k.clone()),
+            Bound::Unbounded => None,
+        };
+        RemoteScan{
+            client: self,
+            prefix: prefix.to_vec(),
+            start,
+            end,
+            page: Vec::new().into_iter(),
+            done: false,
+        }
+    }
+
+    // Sends all of `reqs` without waiting for each response in turn, and
+    // returns their results in the same order. The outer error means the
+    // connection failed, and the requests may or may not have been applied.
+    pub fn pipeline(&mut self, reqs: Vec<Req>) -> Result<Vec<Result<Reply>>> {
+        let first = self.next_id;
+        let mut results: Vec<Option<Result<Reply>>> = reqs.iter().map(|_| None).collect();
+        let mut waiting = 0;
+        for (i, req) in reqs.into_iter().enumerate() {
+            // an older server could not even read it
+            if let Err(error) = req.check_version(self.agreed.version) {
+                results[i] = Some(Err(error));
+                self.next_id += 1;
+                continue;
+            }
+            if waiting == PIPELINE_WINDOW {
+                self.writer.flush()?;
+                self.receive_into(first, &mut results)?;
+                waiting -= 1;
+            }
+            protocol::queue(&mut self.writer, &Request{id: self.next_id, req})?;
+            self.next_id += 1;
+            waiting += 1;
+        }
+        self.writer.flush()?;
+        for _ in 0..waiting {
+            self.receive_into(first, &mut results)?;
+        }
+        Ok(results.into_iter().map(|result| result.expect("every request was answered")).collect())
+    }
+
+    fn request(&mut self, req: Req) -> Result<Reply> {
+        self.pipeline(vec![req])?.remove(0)
+    }
+
+    // Reads one response and stores its result at the position of the
+    // request it answers, counting from the request with id `first`.
+    fn receive_into(&mut self, first: u64, results: &mut [Option<Result<Reply>>]) -> Result<()> {
+        let resp: Response = match protocol::receive(&mut self.reader)? {
+            Some(resp) => resp,
+            None => return Err(connection_closed()),
+        };
+        let slot = resp.id.checked_sub(first)
+            .and_then(|i| results.get_mut(i as usize))
+            .filter(|slot| slot.is_none());
+        match slot {
+            Some(slot) => {
+                *slot = Some(resp.result.map_err(KvsError::from));
+                Ok(())
+            },
+            None => Err(unexpected_response(resp)),
+        }
+    }
+}
+
+// Streams the entries of a scan. Keys the client may not read are skipped.
+pub struct RemoteScan<'a> {
+    client: &'a mut KvsClient,
+    prefix: Vec<u8>,
+    start: Option<Vec<u8>>,
+    end: Option<Vec<u8>>,
+    page: std::vec::IntoIter<KeyValue>,
+    done: bool,
+}
+
+impl RemoteScan<'_> {
+    fn next_page(&mut self) -> Result<()> {
+        let req = Req::Scan{
+            prefix: self.prefix.clone(),
+            start: self.start.take(),
+            end: self.end.clone(),
+            limit: SCAN_PAGE_SIZE,
+        };
+        match self.client.request(req)? {
+            Reply::Page(page) => {
+                self.done = page.next.is_none();
+                self.start = page.next;
+                self.page = page.entries.into_iter();
+                Ok(())
+            },
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+}
+
+impl Iterator for RemoteScan<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(KeyValue{key, value}) = self.page.next() {
+                return Some(Ok((key, value)));
+            }
+            if self.done {
+                return None;
+            }
+            if let Err(error) = self.next_page() {
+                self.done = true;
+                return Some(Err(error));
+            }
+        }
+    }
+}
+
+fn successor(key: &[u8]) -> Vec<u8> {
+    let mut key = key.to_vec();
+    key.push(0);
+    key
+}
+
+// Unpacks the response to the request with the given id.
+pub(crate) fn response_result(resp: Response, id: u64) -> Result<Reply> {
+    if resp.id != id {
+        return Err(unexpected_response(resp));
+    }
+    resp.result.map_err(KvsError::from)
+}
+
+// A response that does not answer any request we are waiting for. The server
+// uses id 0 to reject a request it could not read, so its error is passed on.
+fn unexpected_response(resp: Response) -> KvsError {
+    match resp.result {
+        Err(error) if resp.id == 0 => KvsError::from(error),
+        _ => KvsError::StringError(format!("unexpected response id {} from server", resp.id)),
+    }
+}
+
+pub(crate) fn unexpected_reply(reply: Reply) -> KvsError {
+    KvsError::StringError(format!("unexpected reply from server: {:?}", reply))
+}
+
+pub(crate) fn handshake_result(resp: HelloResponse) -> Result<Hello> {
+    match resp {
+        HelloResponse::Ok(agreed) => Ok(agreed),
+        HelloResponse::Err(msg) => Err(KvsError::Handshake(msg)),
+    }
+}
+
+pub(crate) fn connection_closed() -> KvsError {
+    KvsError::StringError("server closed the connection".to_string())
+}
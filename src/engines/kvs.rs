@@ -1,145 +1,876 @@
-use std::io::{BufRead, BufReader, BufWriter, Read, Write, Seek, ErrorKind};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write, Seek, SeekFrom, ErrorKind};
 use std::path::{self, PathBuf};
-use std::collections::HashMap;
-use serde::{Serialize, Deserialize};
+use std::cell::RefCell;
+use std::collections::{hash_map, BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::ops::{Bound, RangeBounds};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use std::sync::atomic::{AtomicU64, Ordering};
+use serde::Deserialize;
 use std::fs::OpenOptions;
 use crate::{KvsError, Result, KvsEngine};
+use super::{deadline, is_empty_range, now_millis, owned_bounds, spawn_sweeper, BatchOp, ScanIter, WriteBatch};
 use std::fs;
+use log::warn;
 
+// How many entries a scan reads under one lock on the index.
+const SCAN_BATCH: usize = 128;
 
-#[derive(Serialize, Deserialize, Debug)]
+// Compact once this many bytes in the log belong to overwritten or removed keys.
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+
+// Single log file written by earlier versions of the store.
+const LEGACY_LOG: &str = "tmp.log";
+
+#[derive(Debug)]
 enum Entry{
+    Set {key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>},
+    Remove {key: Vec<u8>},
+}
+
+impl Entry {
+    fn expires_at(&self) -> Option<u64> {
+        match self {
+            Entry::Set{expires_at, ..} => *expires_at,
+            Entry::Remove{..} => None,
+        }
+    }
+}
+
+// Entries as they were serialized into version 1 (JSON) records.
+#[derive(Deserialize)]
+enum JsonEntry{
     Set {key: String, value: String},
     Remove {key: String},
 }
 
+impl From<JsonEntry> for Entry {
+    fn from(entry: JsonEntry) -> Entry {
+        match entry {
+            JsonEntry::Set{key, value} => Entry::Set{key: key.into_bytes(), value: value.into_bytes(), expires_at: None},
+            JsonEntry::Remove{key} => Entry::Remove{key: key.into_bytes()},
+        }
+    }
+}
+
+// On-disk format written to `meta.txt` as `kvs <version>`. A bare `kvs`
+// means version 1.
+const FORMAT_VERSION: u32 = 5;
+
+// Binary records are a fixed header followed by the key and value bytes:
+//
+//   tag: u8 | key len: u32 | value len: u32 | crc: u32 | header crc: u32 | key | value
+//
+// Sets of keys with a TTL, added in version 3, carry their deadline, in
+// milliseconds since the Unix epoch, between the header and the key:
+//
+//   tag: u8 | key len: u32 | value len: u32 | crc: u32 | header crc: u32 | expires at: u64 | key | value
+//
+// Batches, added in version 4, are a single record wrapping the records of
+// their writes, so that a torn batch is dropped as a whole on recovery:
+//
+//   tag: u8 | records len: u32 | record count: u32 | crc: u32 | header crc: u32 | records
+//
+// Integers are little endian. The header CRC32, added in version 5, covers the
+// 13 bytes before it, so that a damaged length is caught before it is used to
+// read the rest of the record, and its tag has `TAG_CHECKED_HEADER` set.
+// Records from versions 2 to 4 lack it. The other CRC32 covers every byte of
+// the record but the two checksums. Version 1 records are text lines of the
+// form `<len> <crc> <json>\n` (or bare JSON lines from before checksums were
+// added). Their first byte can never be a tag, so all kinds are read
+// transparently and older records are rewritten by the compaction that runs
+// on open.
+const TAG_SET: u8 = 1;
+const TAG_REMOVE: u8 = 2;
+const TAG_SET_EXPIRING: u8 = 3;
+const TAG_BATCH: u8 = 4;
+const TAG_CHECKED_HEADER: u8 = 0x80;
+const RECORD_HEADER_LEN: usize = 17;
+const LEGACY_RECORD_HEADER_LEN: usize = 13;
+const DEADLINE_LEN: usize = 8;
+const TEXT_RECORD_HEADER_LEN: usize = 18;
+
+// Where the latest entry for a key lives on disk, and when it expires.
+#[derive(Debug, Clone, Copy)]
+struct LogPointer {
+    segment: u64,
+    offset: u64,
+    len: u64,
+    expires_at: Option<u64>,
+}
+
+impl LogPointer {
+    fn has_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|deadline| deadline <= now)
+    }
+}
+
+// The log is split into segments named `<segment>.log`. Only the newest one
+// is appended to; replaying them in ascending order rebuilds the index.
+//
+// Cloning a `KvStore` gives another handle to the same store. Every handle
+// opens its own files for reading, so gets from different handles run in
+// parallel under a shared lock on the index, while writes are serialized
+// through the single writer.
+//
+// The index is ordered by key, so scans walk it in order. They take the lock
+// for one batch of entries at a time rather than for the whole scan.
+//
+// Keys with a TTL read as missing once their deadline passes, and a
+// background thread regularly drops them from the index. Nothing needs to be
+// written for that, since replaying the log skips expired sets as well.
+#[derive(Clone)]
 pub struct KvStore{
-    kv: HashMap<String,String>,
-    writer: BufWriter<std::fs::File>,
-    reader: BufReader<std::fs::File>,
-    pbuf: PathBuf,
+    index: Arc<RwLock<BTreeMap<Vec<u8>, LogPointer>>>,
+    reader: KvStoreReader,
+    writer: Arc<Mutex<KvStoreWriter>>,
+}
+
+struct KvStoreReader {
+    dir: Arc<PathBuf>,
+    // segments older than this have been compacted away
+    safe_point: Arc<AtomicU64>,
+    readers: RefCell<HashMap<u64, BufReader<fs::File>>>,
+}
+
+struct KvStoreWriter {
+    dir: Arc<PathBuf>,
+    index: Arc<RwLock<BTreeMap<Vec<u8>, LogPointer>>>,
+    reader: KvStoreReader,
+    writer: BufWriter<fs::File>,
+    segment: u64,
+    pos: u64,
+    stale: u64,
+    compaction_threshold: u64,
+    // keys with a TTL by deadline, including some that have since been
+    // overwritten or removed
+    expiring: BTreeSet<(u64, Vec<u8>)>,
 }
 
 impl KvStore {
     pub fn open(p: &path::Path) -> Result<KvStore> {
-        let f = p.join("tmp.log");
+        KvStore::open_with_threshold(p, DEFAULT_COMPACTION_THRESHOLD)
+    }
+
+    pub fn open_with_threshold(p: &path::Path, compaction_threshold: u64) -> Result<KvStore> {
         let meta_f = p.join("meta.txt");
-        check_meta(&meta_f)?;
+        match check_meta(&meta_f)? {
+            Some(version) if version > FORMAT_VERSION => return Err(KvsError::UnsupportedFormat(version)),
+            _ => {},
+        }
+        // recorded before anything is upgraded so older versions refuse to
+        // open a log that may already contain new records
         create_meta(&meta_f)?;
+
+        let mut segments = segment_list(p)?;
+        let legacy = p.join(LEGACY_LOG);
+        if segments.is_empty() && legacy.exists() {
+            fs::rename(&legacy, log_path(p, 1))?;
+            segments.push(1);
+        }
+
+        let mut index = BTreeMap::new();
+        let mut readers = HashMap::new();
+        let now = now_millis();
+        let mut stale = 0;
+        let mut pos = 0;
+        let mut legacy_records = false;
+        for (i, &segment) in segments.iter().enumerate() {
+            let mut reader = BufReader::new(fs::File::open(log_path(p, segment))?);
+            let replayed = load_index(segment, &mut reader, &mut index, now)?;
+            legacy_records |= replayed.legacy;
+            let len = match replayed.end {
+                LoadedLen::Complete(len) => len,
+                // only the segment that was being appended to can have a
+                // half-written record at its end
+                LoadedLen::TornAt(len) if i + 1 == segments.len() => {
+                    warn!("truncating torn record at the end of segment {} at offset {}", segment, len);
+                    let f = OpenOptions::new().write(true).open(log_path(p, segment))?;
+                    f.set_len(len)?;
+                    f.sync_all()?;
+                    len
+                },
+                LoadedLen::TornAt(len) => return Err(KvsError::Corruption{segment, offset: len}),
+            };
+            stale += replayed.stale;
+            pos = len;
+            readers.insert(segment, reader);
+        }
+
+        // keep appending to the newest segment
+        let segment = match segments.last() {
+            Some(segment) => *segment,
+            None => {
+                let segment = 1;
+                new_segment(p, segment)?;
+                segment
+            }
+        };
+        let writer = BufWriter::new(OpenOptions::new().append(true).open(log_path(p, segment))?);
+
+        let expiring = index.iter()
+            .filter_map(|(k, ptr)| Some((ptr.expires_at?, k.clone())))
+            .collect();
+        let dir = Arc::new(p.to_path_buf());
+        let index = Arc::new(RwLock::new(index));
+        let reader = KvStoreReader{
+            dir: Arc::clone(&dir),
+            safe_point: Arc::new(AtomicU64::new(0)),
+            readers: RefCell::new(readers),
+        };
+        let mut writer = KvStoreWriter{
+            dir,
+            index: Arc::clone(&index),
+            reader: reader.clone(),
+            writer,
+            segment,
+            pos,
+            stale,
+            compaction_threshold,
+            expiring,
+        };
+        if legacy_records {
+            writer.compact()?;
+        }
+        let writer = Arc::new(Mutex::new(writer));
+        spawn_sweeper(Arc::downgrade(&writer), |writer| writer.lock().unwrap().sweep())?;
         Ok(KvStore{
-            kv: HashMap::new(),
-            writer:  BufWriter::new(OpenOptions::new().create(true).append(true).open(f.clone())?),
-            reader:  BufReader::new(OpenOptions::new().read(true).open(f.clone())?),
-            pbuf: f,
+            index,
+            reader,
+            writer,
         })
     }
+}
 
-    pub fn set(&mut self, k: String, v: String) -> Result<()> {
-        self.kv.insert(k.clone(), v.clone());
-        let val =   Entry::Set{key: k, value: v};
-        serde_json::to_writer(&mut self.writer, &val)?;
-        writeln!(self.writer,"")?;
-        self.writer.flush()?;
-        self.compact()?;
+impl KvsEngine for KvStore{
+    fn set(&self, k: Vec<u8>, v: Vec<u8>) -> Result<()> {
+        self.writer.lock().unwrap().set(k, v, None)
+    }
+
+    fn set_with_ttl(&self, k: Vec<u8>, v: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.writer.lock().unwrap().set(k, v, Some(deadline(ttl)))
+    }
+
+    fn get(&self, k: &[u8]) -> Result<Option<Vec<u8>>> {
+        // the lock is held while reading so that compaction cannot delete
+        // the segment the pointer refers to
+        let index = self.index.read().unwrap();
+        let ptr = match index.get(k) {
+            Some(ptr) if !ptr.has_expired(now_millis()) => *ptr,
+            _ => return Ok(None),
+        };
+        match self.reader.read_entry(ptr)? {
+            Entry::Set{value, ..} => Ok(Some(value)),
+            Entry::Remove{..} => Err(KvsError::UnexpectedEntry),
+        }
+    }
+
+    fn remove(&self, k: &[u8]) -> Result<()> {
+        self.writer.lock().unwrap().remove(k)
+    }
+
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.writer.lock().unwrap().apply_batch(batch)
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<ScanIter> {
+        let (start, end) = owned_bounds(&range);
+        let done = is_empty_range(&start, &end);
+        Ok(Box::new(KvStoreScan{
+            store: self.clone(),
+            start,
+            end,
+            batch: VecDeque::new(),
+            done,
+        }))
+    }
+
+    fn flush(&self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.writer.flush()?;
+        writer.writer.get_ref().sync_all()?;
         Ok(())
     }
+}
 
-    pub fn get(&mut self, k: String) -> Result<Option<String>> {
-        self.populate_hash()?;
-        Ok(self.kv.get(&k).cloned())
+// Reads a scan's range one batch at a time, each starting after the last key
+// of the one before.
+struct KvStoreScan {
+    store: KvStore,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    batch: VecDeque<(Vec<u8>, Vec<u8>)>,
+    done: bool,
+}
+
+impl KvStoreScan {
+    fn next_batch(&mut self) -> Result<()> {
+        let now = now_millis();
+        // held while reading, as in `get`
+        let index = self.store.index.read().unwrap();
+        let mut last = None;
+        for (key, ptr) in index.range((self.start.clone(), self.end.clone())).take(SCAN_BATCH) {
+            last = Some(key);
+            if ptr.has_expired(now) {
+                continue;
+            }
+            match self.store.reader.read_entry(*ptr)? {
+                Entry::Set{value, ..} => self.batch.push_back((key.clone(), value)),
+                Entry::Remove{..} => return Err(KvsError::UnexpectedEntry),
+            }
+        }
+        match last {
+            Some(key) => self.start = Bound::Excluded(key.clone()),
+            None => self.done = true,
+        }
+        if is_empty_range(&self.start, &self.end) {
+            self.done = true;
+        }
+        Ok(())
     }
+}
 
-    pub fn remove(&mut self, k: String) -> Result<()> {
-        self.populate_hash()?;
-        if let Some(_) = self.kv.get(&k) {
-            let val =   Entry::Remove { key: k };
-            serde_json::to_writer(&mut self.writer, &val)?;
-            writeln!(self.writer,"")?;
-            self.writer.flush()?;
-        } else {
-            return Err(KvsError::KeyNotFound)
+impl Iterator for KvStoreScan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.batch.is_empty() && !self.done {
+            if let Err(error) = self.next_batch() {
+                self.done = true;
+                return Some(Err(error));
+            }
+        }
+        self.batch.pop_front().map(Ok)
+    }
+}
+
+impl Clone for KvStoreReader {
+    fn clone(&self) -> KvStoreReader {
+        KvStoreReader{
+            dir: Arc::clone(&self.dir),
+            safe_point: Arc::clone(&self.safe_point),
+            readers: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+impl KvStoreReader {
+    fn read_raw(&self, ptr: LogPointer) -> Result<Vec<u8>> {
+        let mut readers = self.readers.borrow_mut();
+        let safe_point = self.safe_point.load(Ordering::SeqCst);
+        readers.retain(|segment, _| *segment >= safe_point);
+        let reader = match readers.entry(ptr.segment) {
+            hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            hash_map::Entry::Vacant(entry) => {
+                entry.insert(BufReader::new(fs::File::open(log_path(&self.dir, ptr.segment))?))
+            }
+        };
+        reader.seek(SeekFrom::Start(ptr.offset))?;
+        let mut buf = vec![0; ptr.len as usize];
+        reader.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn read_entry(&self, ptr: LogPointer) -> Result<Entry> {
+        let buf = self.read_raw(ptr)?;
+        decode_record(&buf).ok_or(KvsError::Corruption{segment: ptr.segment, offset: ptr.offset})
+    }
+}
+
+impl KvStoreWriter {
+    fn set(&mut self, k: Vec<u8>, v: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        let val = Entry::Set{key: k.clone(), value: v, expires_at};
+        let ptr = self.append(&val)?;
+        let old = self.index.write().unwrap().insert(k.clone(), ptr);
+        if let Some(old) = old {
+            self.stale += old.len;
+            forget_deadline(&mut self.expiring, &old, &k);
+        }
+        if let Some(deadline) = expires_at {
+            self.expiring.insert((deadline, k));
+        }
+        if self.stale > self.compaction_threshold {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, k: &[u8]) -> Result<()> {
+        let now = now_millis();
+        if self.index.read().unwrap().get(k).is_none_or(|ptr| ptr.has_expired(now)) {
+            return Err(KvsError::KeyNotFound);
+        }
+        let val = Entry::Remove { key: k.to_vec() };
+        let ptr = self.append(&val)?;
+        let old = self.index.write().unwrap().remove(k);
+        if let Some(old) = old {
+            // the remove entry itself is never needed after compaction
+            self.stale += old.len + ptr.len;
+            forget_deadline(&mut self.expiring, &old, k);
+        }
+        if self.stale > self.compaction_threshold {
+            self.compact()?;
         }
         Ok(())
     }
 
+    fn apply_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        let now = now_millis();
+        batch.check_removes(|k| {
+            Ok(self.index.read().unwrap().get(k).is_some_and(|ptr| !ptr.has_expired(now)))
+        })?;
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let entries: Vec<Entry> = batch.ops.into_iter().map(|op| match op {
+            BatchOp::Set{key, value} => Entry::Set{key, value, expires_at: None},
+            BatchOp::Remove{key} => Entry::Remove{key},
+        }).collect();
+        let (buf, records) = encode_batch(&entries);
+        self.writer.write_all(&buf)?;
+        self.writer.flush()?;
+        let start = self.pos;
+        self.pos += buf.len() as u64;
+        // the batch header is never needed after compaction
+        self.stale += RECORD_HEADER_LEN as u64;
+        {
+            let mut index = self.index.write().unwrap();
+            for (entry, (offset, len)) in entries.into_iter().zip(records) {
+                let ptr = LogPointer{segment: self.segment, offset: start + offset, len, expires_at: None};
+                match entry {
+                    Entry::Set{key, ..} => {
+                        if let Some(old) = index.insert(key.clone(), ptr) {
+                            self.stale += old.len;
+                            forget_deadline(&mut self.expiring, &old, &key);
+                        }
+                    },
+                    Entry::Remove{key} => {
+                        if let Some(old) = index.remove(&key) {
+                            self.stale += old.len;
+                            forget_deadline(&mut self.expiring, &old, &key);
+                        }
+                        self.stale += len;
+                    },
+                }
+            }
+        }
+        if self.stale > self.compaction_threshold {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, entry: &Entry) -> Result<LogPointer> {
+        let buf = encode_record(entry);
+        self.writer.write_all(&buf)?;
+        self.writer.flush()?;
+        let ptr = LogPointer{segment: self.segment, offset: self.pos, len: buf.len() as u64, expires_at: entry.expires_at()};
+        self.pos += ptr.len;
+        Ok(ptr)
+    }
+
+    // Drops keys whose deadline has passed from the index, leaving their
+    // records for compaction to reclaim. Compaction itself waits for the next
+    // write.
+    fn sweep(&mut self) {
+        let now = now_millis();
+        let mut index = self.index.write().unwrap();
+        while let Some((deadline, _)) = self.expiring.first() {
+            if *deadline > now {
+                break;
+            }
+            let (deadline, key) = self.expiring.pop_first().unwrap();
+            // the key may have been set again since
+            if index.get(&key).is_some_and(|ptr| ptr.expires_at == Some(deadline)) {
+                self.stale += index.remove(&key).unwrap().len;
+            }
+        }
+    }
+
+    // Copies every live entry into a fresh segment and only deletes the old
+    // segments once the new one is safely on disk. Writes continue in the
+    // segment after it, so a crash at any point leaves a log that replays to
+    // the same state.
     fn compact(&mut self) -> Result<()> {
-        let mut compactor: BufWriter<fs::File> = BufWriter::new(OpenOptions::new().create(true).write(true).truncate(true).open(self.pbuf.clone())?);
-        for (k, v) in &mut self.kv.clone().into_iter() {
-            let val =   Entry::Set{key: k, value: v};
-            serde_json::to_writer(&mut compactor, &val)?;
-            writeln!(compactor,"")?;
+        // expired keys are left behind
+        self.sweep();
+        let compaction_segment = self.segment + 1;
+        let tmp_path = self.dir.join(format!("{}.log.tmp", compaction_segment));
+        let mut compactor = BufWriter::new(OpenOptions::new().create(true).write(true).truncate(true).open(&tmp_path)?);
+
+        // only writers change the index and they are serialized behind us,
+        // so the snapshot stays accurate while it is copied
+        let mut pointers: Vec<(Vec<u8>, LogPointer)> = self.index.read().unwrap().iter().map(|(k, ptr)| (k.clone(), *ptr)).collect();
+        pointers.sort_by_key(|(_, ptr)| (ptr.segment, ptr.offset));
+        let mut pos = 0;
+        let mut moved = Vec::with_capacity(pointers.len());
+        for (k, ptr) in pointers {
+            let mut buf = self.reader.read_raw(ptr)?;
+            if !is_current_record(&buf) {
+                let entry = decode_record(&buf).ok_or(KvsError::Corruption{segment: ptr.segment, offset: ptr.offset})?;
+                buf = encode_record(&entry);
+            }
+            compactor.write_all(&buf)?;
+            let len = buf.len() as u64;
+            moved.push((k, LogPointer{segment: compaction_segment, offset: pos, len, expires_at: ptr.expires_at}));
+            pos += len;
         }
         compactor.flush()?;
+        compactor.get_ref().sync_all()?;
+        drop(compactor);
+        fs::rename(&tmp_path, log_path(&self.dir, compaction_segment))?;
+        sync_dir(&self.dir)?;
+
+        self.segment = compaction_segment + 1;
+        new_segment(&self.dir, self.segment)?;
+        self.writer = BufWriter::new(OpenOptions::new().append(true).open(log_path(&self.dir, self.segment))?);
+        self.pos = 0;
+        self.stale = 0;
+
+        {
+            let mut index = self.index.write().unwrap();
+            for (k, ptr) in moved {
+                index.insert(k, ptr);
+            }
+        }
+        // readers drop their handles to older segments on their next get
+        self.reader.safe_point.store(compaction_segment, Ordering::SeqCst);
+
+        for segment in segment_list(&self.dir)? {
+            if segment < compaction_segment {
+                fs::remove_file(log_path(&self.dir, segment))?;
+            }
+        }
         Ok(())
     }
+}
 
-    fn populate_hash(&mut self) -> Result<()> {
-        let mut new_kv: HashMap<String, String> = HashMap::new();
-        for line in self.reader.by_ref().lines() {
-            let sd = line?;
-            let val: Entry = serde_json::from_str(&sd)?;
+// Drops the sweeper's entry for a write that `key` no longer points at.
+fn forget_deadline(expiring: &mut BTreeSet<(u64, Vec<u8>)>, old: &LogPointer, key: &[u8]) {
+    if let Some(deadline) = old.expires_at {
+        expiring.remove(&(deadline, key.to_vec()));
+    }
+}
+
+enum LoadedLen {
+    Complete(u64),
+    // the segment ends in an incomplete or damaged record starting here
+    TornAt(u64),
+}
+
+struct Replayed {
+    end: LoadedLen,
+    // bytes belonging to overwritten or removed keys
+    stale: u64,
+    // whether any records in an older format were seen
+    legacy: bool,
+}
+
+// Replays one segment, recording where the latest entry for every live key
+// starts. Sets that expired before `now` count as removes.
+fn load_index(segment: u64, reader: &mut BufReader<fs::File>, index: &mut BTreeMap<Vec<u8>, LogPointer>, now: u64) -> Result<Replayed> {
+    let mut pos = 0;
+    let mut stale = 0;
+    let mut legacy = false;
+    let mut record = Vec::new();
+    loop {
+        let n = read_record(reader, &mut record)? as u64;
+        if n == 0 {
+            break;
+        }
+        // a batch's writes each get a pointer to their own record inside it
+        let entries = match record_tag(record[0]) {
+            Some(TAG_BATCH) => decode_batch(&record),
+            _ => decode_record(&record).map(|val| vec![(0, n, val)]),
+        };
+        let entries = match entries {
+            Some(entries) => entries,
+            // a full-length last record can fail its checks too, if the
+            // write was torn in the middle rather than cut short
+            None if is_truncated(&record) || reader.fill_buf()?.is_empty() => {
+                return Ok(Replayed{end: LoadedLen::TornAt(pos), stale, legacy})
+            },
+            None => return Err(KvsError::Corruption{segment, offset: pos}),
+        };
+        legacy |= !is_current_record(&record);
+        if record_tag(record[0]) == Some(TAG_BATCH) {
+            stale += header_len(record[0]) as u64;
+        }
+        for (offset, len, val) in entries {
+            let ptr = LogPointer{segment, offset: pos + offset, len, expires_at: val.expires_at()};
             match val {
-                Entry::Set {key, value} => {
-                    new_kv.insert(key, value);
+                Entry::Set {key, ..} if !ptr.has_expired(now) => {
+                    if let Some(old) = index.insert(key, ptr) {
+                        stale += old.len;
+                    }
                 },
-                Entry::Remove { key} => {
-                    new_kv.remove(&key);
+                Entry::Set {key, ..} | Entry::Remove { key} => {
+                    if let Some(old) = index.remove(&key) {
+                        stale += old.len;
+                    }
+                    stale += len;
                 }
             }
         }
-        self.reader.rewind()?;
-        self.kv = new_kv;
-        self.compact()?;
-        Ok(())
+        pos += n;
     }
+    Ok(Replayed{end: LoadedLen::Complete(pos), stale, legacy})
 }
 
-impl KvsEngine for KvStore{
-    fn set(&mut self, k: String, v: String) -> Result<()> {
-        self.kv.insert(k.clone(), v.clone());
-        let val =   Entry::Set{key: k, value: v};
-        serde_json::to_writer(&mut self.writer, &val)?;
-        writeln!(self.writer,"")?;
-        self.writer.flush()?;
-        self.compact()?;
-        Ok(())
+// Reads the raw bytes of the next record into `buf`, returning how many were
+// read. A record cut short by the end of the file, or whose header is damaged,
+// is returned as is.
+fn read_record(reader: &mut BufReader<fs::File>, buf: &mut Vec<u8>) -> io::Result<usize> {
+    buf.clear();
+    match reader.fill_buf()?.first() {
+        None => Ok(0),
+        Some(&first) if record_tag(first).is_some() => {
+            let header_len = header_len(first);
+            reader.by_ref().take(header_len as u64).read_to_end(buf)?;
+            if buf.len() < header_len || !header_intact(buf) {
+                return Ok(buf.len());
+            }
+            let body_len = record_len(buf).unwrap() - header_len;
+            reader.by_ref().take(body_len as u64).read_to_end(buf)?;
+            Ok(buf.len())
+        },
+        Some(_) => reader.read_until(b'\n', buf),
+    }
+}
+
+// Whether a record that failed to decode was cut short by the end of the
+// segment, as opposed to damaged in place.
+fn is_truncated(buf: &[u8]) -> bool {
+    match buf.first() {
+        Some(&first) if record_tag(first).is_some() => {
+            buf.len() < header_len(first) || (header_intact(buf) && buf.len() < record_len(buf).unwrap())
+        },
+        _ => !buf.ends_with(b"\n"),
+    }
+}
+
+// The kind of binary record starting with `first`, if it starts one.
+fn record_tag(first: u8) -> Option<u8> {
+    let tag = first & !TAG_CHECKED_HEADER;
+    matches!(tag, TAG_SET | TAG_REMOVE | TAG_SET_EXPIRING | TAG_BATCH).then_some(tag)
+}
+
+fn header_len(first: u8) -> usize {
+    if first & TAG_CHECKED_HEADER != 0 {
+        RECORD_HEADER_LEN
+    } else {
+        LEGACY_RECORD_HEADER_LEN
+    }
+}
+
+fn is_binary_record(buf: &[u8]) -> bool {
+    buf.first().is_some_and(|&first| record_tag(first).is_some())
+}
+
+// Whether the record is in the format written by this version.
+fn is_current_record(buf: &[u8]) -> bool {
+    is_binary_record(buf) && buf[0] & TAG_CHECKED_HEADER != 0
+}
+
+// Whether the lengths in a complete binary header can be trusted. Headers
+// from before version 5 have no checksum of their own.
+fn header_intact(buf: &[u8]) -> bool {
+    if buf[0] & TAG_CHECKED_HEADER == 0 {
+        return true;
     }
+    let header_crc = u32::from_le_bytes(buf[13..RECORD_HEADER_LEN].try_into().unwrap());
+    crc32fast::hash(&buf[..13]) == header_crc
+}
 
-    fn get(&mut self, k: String) -> Result<Option<String>> {
-        self.populate_hash()?;
-        Ok(self.kv.get(&k).cloned())
+// The full length of the binary record whose header starts `buf`.
+fn record_len(buf: &[u8]) -> Option<usize> {
+    let key_len = u32::from_le_bytes(buf.get(1..5)?.try_into().ok()?) as usize;
+    let value_len = u32::from_le_bytes(buf.get(5..9)?.try_into().ok()?) as usize;
+    let body_len = match record_tag(*buf.first()?)? {
+        TAG_SET_EXPIRING => DEADLINE_LEN + key_len + value_len,
+        // the second field counts records rather than bytes
+        TAG_BATCH => key_len,
+        _ => key_len + value_len,
+    };
+    Some(header_len(buf[0]) + body_len)
+}
+
+fn encode_record(entry: &Entry) -> Vec<u8> {
+    let (tag, key, value) = match entry {
+        Entry::Set{key, value, expires_at: None} => (TAG_SET, key.as_slice(), value.as_slice()),
+        Entry::Set{key, value, expires_at: Some(_)} => (TAG_SET_EXPIRING, key.as_slice(), value.as_slice()),
+        Entry::Remove{key} => (TAG_REMOVE, key.as_slice(), &[][..]),
+    };
+    let mut buf = Vec::with_capacity(RECORD_HEADER_LEN + DEADLINE_LEN + key.len() + value.len());
+    buf.push(tag | TAG_CHECKED_HEADER);
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&[0; 8]);
+    if let Some(deadline) = entry.expires_at() {
+        buf.extend_from_slice(&deadline.to_le_bytes());
     }
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(value);
+    seal(&mut buf);
+    buf
+}
 
-    fn remove(&mut self, k: String) -> Result<()> {
-        self.populate_hash()?;
-        if let Some(_) = self.kv.get(&k) {
-            let val =   Entry::Remove { key: k };
-            serde_json::to_writer(&mut self.writer, &val)?;
-            writeln!(self.writer,"")?;
-            self.writer.flush()?;
-        } else {
-            return Err(KvsError::KeyNotFound)
+// Returns the batch record and where each entry's own record sits in it.
+fn encode_batch(entries: &[Entry]) -> (Vec<u8>, Vec<(u64, u64)>) {
+    let mut buf = vec![TAG_BATCH | TAG_CHECKED_HEADER];
+    buf.extend_from_slice(&[0; RECORD_HEADER_LEN - 1]);
+    let mut records = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let record = encode_record(entry);
+        records.push((buf.len() as u64, record.len() as u64));
+        buf.extend_from_slice(&record);
+    }
+    let records_len = (buf.len() - RECORD_HEADER_LEN) as u32;
+    buf[1..5].copy_from_slice(&records_len.to_le_bytes());
+    buf[5..9].copy_from_slice(&(entries.len() as u32).to_le_bytes());
+    seal(&mut buf);
+    (buf, records)
+}
+
+// Returns the entries of an intact batch record with the offset and length
+// of each one's own record, or `None` for anything else.
+fn decode_batch(buf: &[u8]) -> Option<Vec<(u64, u64, Entry)>> {
+    let header_len = header_len(*buf.first()?);
+    if record_tag(buf[0]) != Some(TAG_BATCH) || buf.len() < header_len || !header_intact(buf) || buf.len() != record_len(buf)? {
+        return None;
+    }
+    let count = u32::from_le_bytes(buf[5..9].try_into().ok()?) as usize;
+    let crc = u32::from_le_bytes(buf[9..13].try_into().ok()?);
+    if record_crc(buf) != crc {
+        return None;
+    }
+    let mut entries = Vec::with_capacity(count);
+    let mut pos = header_len;
+    while pos < buf.len() {
+        let len = record_len(&buf[pos..]).filter(|_| record_tag(buf[pos]) != Some(TAG_BATCH))?;
+        entries.push((pos as u64, len as u64, decode_record(buf.get(pos..pos + len)?)?));
+        pos += len;
+    }
+    (entries.len() == count).then_some(entries)
+}
+
+fn record_crc(buf: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&buf[..9]);
+    hasher.update(&buf[header_len(buf[0])..]);
+    hasher.finalize()
+}
+
+// Fills in both checksums of a record written with zeroes in their place.
+fn seal(buf: &mut [u8]) {
+    let crc = record_crc(buf);
+    buf[9..13].copy_from_slice(&crc.to_le_bytes());
+    let header_crc = crc32fast::hash(&buf[..13]);
+    buf[13..RECORD_HEADER_LEN].copy_from_slice(&header_crc.to_le_bytes());
+}
+
+// Returns `None` for anything that is not a complete, intact record.
+fn decode_record(buf: &[u8]) -> Option<Entry> {
+    let tag = match buf.first().and_then(|&first| record_tag(first)) {
+        Some(tag) => tag,
+        None => return decode_text_record(buf),
+    };
+    let header_len = header_len(buf[0]);
+    // batches are read with `decode_batch`
+    if buf.len() < header_len || tag == TAG_BATCH || !header_intact(buf) {
+        return None;
+    }
+    let key_len = u32::from_le_bytes(buf[1..5].try_into().ok()?) as usize;
+    let value_len = u32::from_le_bytes(buf[5..9].try_into().ok()?) as usize;
+    let crc = u32::from_le_bytes(buf[9..13].try_into().ok()?);
+    let (expires_at, start) = match tag {
+        TAG_SET_EXPIRING => {
+            let deadline = buf.get(header_len..header_len + DEADLINE_LEN)?;
+            (Some(u64::from_le_bytes(deadline.try_into().ok()?)), header_len + DEADLINE_LEN)
+        },
+        _ => (None, header_len),
+    };
+    if buf.len() != start + key_len + value_len || record_crc(buf) != crc {
+        return None;
+    }
+    let key = buf[start..start + key_len].to_vec();
+    match tag {
+        TAG_SET | TAG_SET_EXPIRING => {
+            let value = buf[start + key_len..].to_vec();
+            Some(Entry::Set{key, value, expires_at})
+        },
+        _ => Some(Entry::Remove{key}),
+    }
+}
+
+fn decode_text_record(line: &[u8]) -> Option<Entry> {
+    let line = line.strip_suffix(b"\n")?;
+    if line.first() == Some(&b'{') {
+        return serde_json::from_slice::<JsonEntry>(line).ok().map(Entry::from);
+    }
+    if line.len() < TEXT_RECORD_HEADER_LEN || line[8] != b' ' || line[17] != b' ' {
+        return None;
+    }
+    let len = usize::from_str_radix(std::str::from_utf8(&line[..8]).ok()?, 16).ok()?;
+    let crc = u32::from_str_radix(std::str::from_utf8(&line[9..17]).ok()?, 16).ok()?;
+    let payload = &line[TEXT_RECORD_HEADER_LEN..];
+    if payload.len() != len || crc32fast::hash(payload) != crc {
+        return None;
+    }
+    serde_json::from_slice::<JsonEntry>(payload).ok().map(Entry::from)
+}
+
+fn log_path(dir: &path::Path, segment: u64) -> PathBuf {
+    dir.join(format!("{}.log", segment))
+}
+
+fn new_segment(dir: &path::Path, segment: u64) -> Result<()> {
+    OpenOptions::new().create(true).append(true).open(log_path(dir, segment))?;
+    Ok(())
+}
+
+// Returns the ids of all segments in the directory, oldest first. Leftovers
+// from an interrupted compaction are removed.
+fn segment_list(dir: &path::Path) -> Result<Vec<u64>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = match path.file_name().and_then(|n| n.to_str()) {
+            Some(name) => name,
+            None => continue,
+        };
+        if name.ends_with(".log.tmp") {
+            fs::remove_file(&path)?;
+        } else if let Some(segment) = name.strip_suffix(".log").and_then(|s| s.parse::<u64>().ok()) {
+            segments.push(segment);
         }
-        Ok(())
     }
+    segments.sort_unstable();
+    Ok(segments)
 }
 
-fn check_meta (f: &path::PathBuf) -> Result<()> {
-    let res = OpenOptions::new().read(true).open(f.clone());
+fn sync_dir(dir: &path::Path) -> io::Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
+// Returns the format version recorded in the meta file, if there is one.
+fn check_meta (f: &path::Path) -> Result<Option<u32>> {
+    let res = OpenOptions::new().read(true).open(f);
     match res {
         Ok(f) => {
             let s = read_meta(f);
-            if s == "kvs" {
-                return Ok(());
+            match s.split_once(' ') {
+                None if s == "kvs" => Ok(Some(1)),
+                Some(("kvs", version)) => version.parse().map(Some).map_err(|_| KvsError::WrongMeta),
+                _ => Err(KvsError::WrongMeta),
             }
-            return Err(KvsError::WrongMeta);
         },
         Err(err) => { match err.kind() {
             ErrorKind::NotFound => {
-                return Ok(());
+                Ok(None)
             },
             _ => {
-                return Err(KvsError::from(err));
+                Err(KvsError::from(err))
             }
         }
         }
@@ -148,14 +879,14 @@ fn check_meta (f: &path::PathBuf) -> Result<()> {
 
 fn read_meta(mut f: fs::File) -> String {
     let mut s = String::new();
-    if let Err(e) = f.read_to_string(&mut s) {
+    if f.read_to_string(&mut s).is_err() {
         return "".to_string();
     }
     s
 }
 
-fn create_meta(pbuf: &path::PathBuf) -> Result<()> {
-    let mut f = OpenOptions::new().create(true).write(true).truncate(true).open(pbuf.clone())?;
-    f.write_all("kvs".as_bytes())?;
+fn create_meta(pbuf: &path::Path) -> Result<()> {
+    let mut f = OpenOptions::new().create(true).write(true).truncate(true).open(pbuf)?;
+    write!(f, "kvs {}", FORMAT_VERSION)?;
     Ok(())
-}
\ No newline at end of file
+}
@@ -1,68 +1,248 @@
-use super::KvsEngine;
+use super::{deadline, is_empty_range, now_millis, owned_bounds, spawn_sweeper, BatchOp, KvsEngine, ScanIter, WriteBatch};
 use crate::{KvsError, Result};
-use std::path::{self, PathBuf};
+use std::ops::RangeBounds;
+use std::path;
 use std::fs::OpenOptions;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use log::warn;
 
 use std::fs;
 use std::io::{Read, Write, ErrorKind};
 
+// On-disk format written to `meta.txt` as `sled <version>`. A bare `sled`
+// means version 1, which kept values as they are in the default tree and,
+// for a while, their deadlines in a tree of their own.
+const FORMAT_VERSION: u32 = 2;
+
+// Values are kept in the "values" tree behind the big endian deadline of
+// their key, in milliseconds since the Unix epoch, or 0 if it never expires:
+//
+//   expires at: u64 | value
+//
+// so that a single insert replaces a value together with its deadline and
+// readers need no lock. The "expiry" tree indexes keys with a TTL by
+// deadline, then key, for the sweeper. It is only a hint, written before the
+// value and checked against it, so stale entries are harmless.
+//
+// Writes go through a single writer so that the sweeper cannot remove a key
+// that has just been set again, and a batch sees no other write between
+// checking its removes and applying them.
+#[derive(Clone)]
 pub struct Sled {
     db: sled::Db,
+    values: sled::Tree,
+    writer: Arc<Mutex<SledWriter>>,
+}
+
+struct SledWriter {
+    values: sled::Tree,
+    expiry: sled::Tree,
 }
 
 impl Sled {
     pub fn open(p: &path::Path) -> Result<Sled> {
         let meta_f = p.join("meta.txt");
-        check_meta(&meta_f)?;
+        let version = check_meta(&meta_f)?;
+        match version {
+            Some(version) if version > FORMAT_VERSION => return Err(KvsError::UnsupportedFormat(version)),
+            _ => {},
+        }
+        let db = sled::open(p)?;
+        let values = db.open_tree("values")?;
+        let expiry = db.open_tree("expiry")?;
+        if version == Some(1) {
+            upgrade(&db, &values, &expiry)?;
+        }
         create_meta(&meta_f)?;
-        let tree = sled::open(p)?;
-        Ok(Sled{db: tree})
+        if version == Some(1) {
+            // dropping the tree instead leaves sled unable to open the store
+            db.clear()?;
+            db.open_tree("ttl")?.clear()?;
+            db.flush()?;
+        }
+        let writer = Arc::new(Mutex::new(SledWriter{values: values.clone(), expiry}));
+        spawn_sweeper(Arc::downgrade(&writer), |writer| {
+            if let Err(error) = writer.lock().unwrap().sweep() {
+                warn!("error removing expired keys: {}", error);
+            }
+        })?;
+        Ok(Sled{db, values, writer})
     }
 }
 
-impl KvsEngine for Sled {
-    fn set(&mut self, k: String, v: String) -> Result<()> {
-        self.db.insert(k,v)?;
-        self.db.flush()?;
+impl SledWriter {
+    fn set(&self, k: Vec<u8>, v: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        if let Some(deadline) = expires_at {
+            self.expiry.insert(expiry_key(deadline, &k), Vec::new())?;
+        }
+        self.values.insert(k, encode_value(expires_at, &v))?;
         Ok(())
     }
-    fn get(&mut self, k: String) -> Result<Option<String>> {
-        Ok(self.db
-            .get(k)?
-            .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
-            .map(String::from_utf8)
-            .transpose()?)
+
+    fn remove(&self, k: &[u8]) -> Result<()> {
+        match self.values.remove(k)? {
+            Some(stored) if decode_value(&stored, now_millis())?.is_some() => Ok(()),
+            _ => Err(KvsError::KeyNotFound),
+        }
     }
-    fn remove(&mut self, k: String) -> Result<()> {
-        let res = self.db.remove(k)?;
-        self.db.flush()?;
-        match res {
-            Some(_) => {
-                return Ok(());
-            },
-            None => {
-                return Err(KvsError::KeyNotFound);
+
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        let now = now_millis();
+        batch.check_removes(|k| match self.values.get(k)? {
+            Some(stored) => Ok(decode_value(&stored, now)?.is_some()),
+            None => Ok(false),
+        })?;
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let mut writes = sled::Batch::default();
+        for op in batch.ops {
+            match op {
+                BatchOp::Set{key, value} => writes.insert(key, encode_value(None, &value)),
+                BatchOp::Remove{key} => writes.remove(key),
             }
         }
+        self.values.apply_batch(writes)?;
+        Ok(())
+    }
+
+    // Removes the keys whose deadline has passed, stopping at the first
+    // deadline that has not.
+    fn sweep(&self) -> Result<()> {
+        let now = now_millis();
+        let mut removed = false;
+        for entry in self.expiry.range(..now.saturating_add(1).to_be_bytes().to_vec()) {
+            let (hint, _) = entry?;
+            if hint.len() < 8 {
+                return Err(KvsError::UnexpectedEntry);
+            }
+            let (deadline, key) = hint.split_at(8);
+            if let Some(stored) = self.values.get(key)? {
+                if stored.get(..8) == Some(deadline) {
+                    self.values.remove(key)?;
+                }
+            }
+            self.expiry.remove(&hint)?;
+            removed = true;
+        }
+        if removed {
+            self.values.flush()?;
+        }
+        Ok(())
+    }
+}
+
+fn expiry_key(deadline: u64, k: &[u8]) -> Vec<u8> {
+    let mut hint = deadline.to_be_bytes().to_vec();
+    hint.extend_from_slice(k);
+    hint
+}
+
+fn encode_value(expires_at: Option<u64>, v: &[u8]) -> Vec<u8> {
+    let mut stored = expires_at.unwrap_or(0).to_be_bytes().to_vec();
+    stored.extend_from_slice(v);
+    stored
+}
+
+// The value in `stored`, or `None` if it has expired by `now`.
+fn decode_value(stored: &[u8], now: u64) -> Result<Option<&[u8]>> {
+    if stored.len() < 8 {
+        return Err(KvsError::UnexpectedEntry);
+    }
+    let (deadline, value) = stored.split_at(8);
+    match u64::from_be_bytes(deadline.try_into().unwrap()) {
+        0 => Ok(Some(value)),
+        deadline if deadline > now => Ok(Some(value)),
+        _ => Ok(None),
+    }
+}
+
+// Copies a version 1 store into the "values" and "expiry" trees. The values
+// go in a single batch, so an upgrade that is cut short is simply redone
+// the next time the store is opened.
+fn upgrade(db: &sled::Db, values: &sled::Tree, expiry: &sled::Tree) -> Result<()> {
+    let deadlines = db.open_tree("ttl")?;
+    let mut writes = sled::Batch::default();
+    for entry in db.iter() {
+        let (key, value) = entry?;
+        let expires_at = match deadlines.get(&key)? {
+            Some(deadline) => {
+                let deadline = deadline.as_ref().try_into().map(u64::from_be_bytes).map_err(|_| KvsError::UnexpectedEntry)?;
+                expiry.insert(expiry_key(deadline, &key), Vec::new())?;
+                Some(deadline)
+            },
+            None => None,
+        };
+        writes.insert(key.to_vec(), encode_value(expires_at, &value));
+    }
+    values.apply_batch(writes)?;
+    db.flush()?;
+    Ok(())
+}
+
+impl KvsEngine for Sled {
+    fn set(&self, k: Vec<u8>, v: Vec<u8>) -> Result<()> {
+        self.writer.lock().unwrap().set(k, v, None)?;
+        self.flush()
+    }
+    fn set_with_ttl(&self, k: Vec<u8>, v: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.writer.lock().unwrap().set(k, v, Some(deadline(ttl)))?;
+        self.flush()
+    }
+    fn get(&self, k: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.values.get(k)? {
+            Some(stored) => Ok(decode_value(&stored, now_millis())?.map(<[u8]>::to_vec)),
+            None => Ok(None),
+        }
+    }
+    fn remove(&self, k: &[u8]) -> Result<()> {
+        let res = self.writer.lock().unwrap().remove(k);
+        self.flush()?;
+        res
+    }
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.writer.lock().unwrap().apply_batch(batch)?;
+        self.flush()
+    }
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<ScanIter> {
+        let (start, end) = owned_bounds(&range);
+        if is_empty_range(&start, &end) {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        let now = now_millis();
+        let entries = self.values.range((start, end)).filter_map(move |entry| {
+            let res = entry.map_err(KvsError::from).and_then(|(key, stored)| {
+                Ok(decode_value(&stored, now)?.map(|value| (key.to_vec(), value.to_vec())))
+            });
+            res.transpose()
+        });
+        Ok(Box::new(entries))
+    }
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
     }
 }
 
-fn check_meta (f: &path::PathBuf) -> Result<()> {
-    let res = OpenOptions::new().read(true).open(f.clone());
+// Returns the format version recorded in the meta file, if there is one.
+fn check_meta (f: &path::Path) -> Result<Option<u32>> {
+    let res = OpenOptions::new().read(true).open(f);
     match res {
         Ok(f) => {
             let s = read_meta(f);
-            if s == "sled" {
-                return Ok(());
+            match s.split_once(' ') {
+                None if s == "sled" => Ok(Some(1)),
+                Some(("sled", version)) => version.parse().map(Some).map_err(|_| KvsError::WrongMeta),
+                _ => Err(KvsError::WrongMeta),
             }
-            return Err(KvsError::WrongMeta);
         },
         Err(err) => { match err.kind() {
             ErrorKind::NotFound => {
-                return Ok(());
+                Ok(None)
             },
             _ => {
-                return Err(KvsError::from(err));
+                Err(KvsError::from(err))
             }
         }
         }
@@ -71,14 +251,14 @@ fn check_meta (f: &path::PathBuf) -> Result<()> {
 
 fn read_meta(mut f: fs::File) -> String {
     let mut s = String::new();
-    if let Err(e) = f.read_to_string(&mut s) {
+    if f.read_to_string(&mut s).is_err() {
         return "".to_string();
     }
     s
 }
 
-fn create_meta(pbuf: &path::PathBuf) -> Result<()> {
-    let mut f = OpenOptions::new().create(true).write(true).truncate(true).open(pbuf.clone())?;
-    f.write_all("sled".as_bytes())?;
+fn create_meta(pbuf: &path::Path) -> Result<()> {
+    let mut f = OpenOptions::new().create(true).write(true).truncate(true).open(pbuf)?;
+    write!(f, "sled {}", FORMAT_VERSION)?;
     Ok(())
 }
\ No newline at end of file
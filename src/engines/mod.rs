@@ -1,12 +1,157 @@
-use crate::Result;
+use std::collections::HashMap;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Weak;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::{KvsError, Result};
 
-pub trait KvsEngine {
-    fn set(&mut self, k: String, v: String) -> Result<()>;
-    fn get(&mut self, k: String) -> Result<Option<String>>;
-    fn remove(&mut self, k: String) -> Result<()>;
+// Engines are cheap to clone and every clone is a handle to the same
+// underlying store, so one can be handed to each thread serving requests.
+pub trait KvsEngine: Clone + Send + 'static {
+    fn set(&self, k: Vec<u8>, v: Vec<u8>) -> Result<()>;
+    // Like `set`, but the key disappears once `ttl` has passed. Expired keys
+    // read as missing straight away and are cleaned up in the background.
+    fn set_with_ttl(&self, k: Vec<u8>, v: Vec<u8>, ttl: Duration) -> Result<()>;
+    fn get(&self, k: &[u8]) -> Result<Option<Vec<u8>>>;
+    fn remove(&self, k: &[u8]) -> Result<()>;
+    // Applies every write in `batch`, in order, or none of them: a crash
+    // part way leaves none on disk, and a remove of a missing key fails the
+    // whole batch with `KvsError::KeyNotFound`.
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()>;
+    // The keys in `range` and their values, in key order. The iterator holds
+    // no locks between items, so writes made while it runs may or may not
+    // show up in it.
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<ScanIter>;
+    // Like `scan`, for the keys starting with `prefix`.
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<ScanIter> {
+        self.scan(prefix_range(prefix))
+    }
+    // Makes sure everything written so far is durable on disk.
+    fn flush(&self) -> Result<()>;
+}
+
+// Key/value pairs returned by a scan.
+pub type ScanIter = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>>;
+
+// Writes to apply together with `KvsEngine::apply_batch`. See
+// `protocol::WireBatch` for how they are sent to a server.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<BatchOp>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum BatchOp {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Remove { key: Vec<u8> },
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    pub fn set(&mut self, k: Vec<u8>, v: Vec<u8>) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Set{key: k, value: v});
+        self
+    }
+
+    pub fn remove(&mut self, k: Vec<u8>) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Remove{key: k});
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    // The keys the batch sets, and those it removes.
+    fn keys(&self) -> impl Iterator<Item = (&[u8], bool)> {
+        self.ops.iter().map(|op| match op {
+            BatchOp::Set{key, ..} => (key.as_slice(), true),
+            BatchOp::Remove{key} => (key.as_slice(), false),
+        })
+    }
+
+    // Fails with `KeyNotFound` if a remove in the batch would find nothing,
+    // given which keys `exists` before it is applied.
+    fn check_removes<F: Fn(&[u8]) -> Result<bool>>(&self, exists: F) -> Result<()> {
+        let mut written = HashMap::new();
+        for (key, set) in self.keys() {
+            let found = match written.get(key) {
+                Some(found) => *found,
+                None => exists(key)?,
+            };
+            if !set && !found {
+                return Err(KvsError::KeyNotFound);
+            }
+            written.insert(key, set);
+        }
+        Ok(())
+    }
 }
 
 mod kvs;
 pub use self::kvs::KvStore;
 mod kvsled;
-pub use self::kvsled::Sled;
\ No newline at end of file
+pub use self::kvsled::Sled;
+
+// The range of keys starting with `prefix`: up to, but excluding, the first
+// key greater than all of them, if there is one.
+pub(crate) fn prefix_range(prefix: &[u8]) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return (Bound::Included(prefix.to_vec()), Bound::Excluded(end));
+        }
+    }
+    (Bound::Included(prefix.to_vec()), Bound::Unbounded)
+}
+
+fn owned_bounds<R: RangeBounds<Vec<u8>>>(range: &R) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    (range.start_bound().cloned(), range.end_bound().cloned())
+}
+
+// Whether a range can contain any key. `BTreeMap::range` panics on the ones
+// that cannot.
+fn is_empty_range(start: &Bound<Vec<u8>>, end: &Bound<Vec<u8>>) -> bool {
+    match (start, end) {
+        (Bound::Unbounded, _) | (_, Bound::Unbounded) => false,
+        (Bound::Included(s), Bound::Included(e)) => s > e,
+        (Bound::Included(s) | Bound::Excluded(s), Bound::Excluded(e) | Bound::Included(e)) => s >= e,
+    }
+}
+
+// How often expired keys are swept out of an engine.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+// Deadlines are kept as milliseconds since the Unix epoch, so that they still
+// mean the same thing after a restart.
+fn deadline(ttl: Duration) -> u64 {
+    now_millis().saturating_add(u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX))
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// Calls `sweep` every `SWEEP_INTERVAL` until every other handle to `target`
+// has been dropped.
+fn spawn_sweeper<T: Send + Sync + 'static>(target: Weak<T>, sweep: fn(&T)) -> Result<()> {
+    thread::Builder::new().name("kvs-sweeper".to_string()).spawn(move || loop {
+        thread::sleep(SWEEP_INTERVAL);
+        match target.upgrade() {
+            Some(target) => sweep(&target),
+            None => break,
+        }
+    })?;
+    Ok(())
+}
@@ -1,25 +1,67 @@
 use::std::io;
 use std::string::FromUtf8Error;
 
-use failure::Fail;
+use std::error::Error;
+use std::fmt;
 
-#[derive(Fail, Debug)]
-pub enum KvsError{
-    #[fail(display = "{}", _0)]
-    Io(#[cause] io::Error),
-
-    #[fail(display = "{}", _0)]
-    Serde(#[cause] serde_json::Error),
+use crate::ErrorCode;
 
-    #[fail(display = "Key not found")]
+#[derive(Debug)]
+pub enum KvsError{
+    Io(io::Error),
+    Serde(serde_json::Error),
     KeyNotFound,
-
-    #[fail(display = "wrong meta")]
+    UnexpectedEntry,
+    Corruption { segment: u64, offset: u64 },
+    UnsupportedFormat(u32),
+    StringError(String),
+    FrameTooLarge(u64),
+    Handshake(String),
+    Unauthorized(String),
+    Forbidden(String),
+    Tls(String),
+    Protocol(String),
+    Remote { code: ErrorCode, message: String },
     WrongMeta,
+    UTF8(FromUtf8Error),
+}
 
-    #[fail(display = "{}", _0)]
-    UTF8(#[cause] FromUtf8Error),
+impl fmt::Display for KvsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KvsError::Io(err) => write!(f, "{}", err),
+            KvsError::Serde(err) => write!(f, "{}", err),
+            KvsError::KeyNotFound => write!(f, "Key not found"),
+            KvsError::UnexpectedEntry => write!(f, "Unexpected entry in log"),
+            KvsError::Corruption { segment, offset } => {
+                write!(f, "Corrupt log record in segment {} at offset {}", segment, offset)
+            },
+            KvsError::UnsupportedFormat(version) => write!(f, "Unsupported log format version {}", version),
+            KvsError::StringError(msg) => write!(f, "{}", msg),
+            KvsError::FrameTooLarge(len) => write!(f, "Frame of {} bytes is larger than allowed", len),
+            KvsError::Handshake(msg) => write!(f, "Handshake failed: {}", msg),
+            KvsError::Unauthorized(msg) => write!(f, "Unauthorized: {}", msg),
+            KvsError::Forbidden(msg) => write!(f, "Forbidden: {}", msg),
+            KvsError::Tls(msg) => write!(f, "TLS error: {}", msg),
+            KvsError::Protocol(msg) => write!(f, "Protocol error: {}", msg),
+            KvsError::Remote { message, .. } => write!(f, "{}", message),
+            KvsError::WrongMeta => write!(f, "wrong meta"),
+            KvsError::UTF8(err) => write!(f, "{}", err),
+        }
+    }
 }
+
+impl Error for KvsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            KvsError::Io(err) => Some(err),
+            KvsError::Serde(err) => Some(err),
+            KvsError::UTF8(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
 pub type Result<T> = std::result::Result<T, KvsError>;
 
 impl From<io::Error> for KvsError {
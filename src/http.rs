@@ -0,0 +1,277 @@
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+use crate::acl::Operation;
+use crate::auth::Session;
+use crate::protocol::{read_payload, ErrorCode, ResponseError, MAX_FRAME_SIZE};
+use crate::resp::{read_line, unexpected_eof};
+use crate::net::Stream;
+use crate::server::{scan_page, wait_for_message};
+use crate::{KvsEngine, KvsError, Result};
+
+// Most header lines a request may have.
+const MAX_HEADERS: usize = 100;
+
+// How many entries `GET /keys` returns when not asked for a limit.
+const DEFAULT_SCAN_LIMIT: u32 = 100;
+
+// The HTTP API:
+//
+//   GET    /health      200 once the server is up
+//   GET    /keys/{key}  200 with the value as the body, 404 if there is none
+//   PUT    /keys/{key}  204, the body is the new value
+//   DELETE /keys/{key}  204, 404 if there is no such key
+//   GET    /keys        200 with a page of keys and values, see below
+//
+// Keys are percent-encoded in the path. `GET /keys` takes the optional query
+// parameters `prefix`, `start` (inclusive), `end` (exclusive) and `limit`,
+// and answers with a page as in the kvs protocol: JSON holding the entries,
+// with keys and values in base64, and `next`, the `start` of the following
+// page if there is one. Errors come back as JSON holding an
+// error code and a message, the same as in the kvs protocol. When the server
+// requires authentication, every request but health checks must carry a
+// token as `Authorization: Bearer <token>`; `session` is where each request's
+// authentication starts from.
+pub(crate) fn serve<E: KvsEngine>(engine: &E, ts: Stream, session: Session) -> Result<()> {
+    let mut reader = BufReader::new(&ts);
+    let mut writer = BufWriter::new(&ts);
+    loop {
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+        if !wait_for_message(&ts, &mut reader)? {
+            break;
+        }
+        let req = match read_request(&mut reader) {
+            Ok(Some(req)) => req,
+            Ok(None) => break,
+            Err(error @ (KvsError::Protocol(_) | KvsError::FrameTooLarge(_))) => {
+                let mut resp = error_response(ResponseError{code: ErrorCode::InvalidRequest, message: error.to_string()});
+                if let KvsError::FrameTooLarge(_) = error {
+                    resp.status = 413;
+                }
+                let _ = write_response(&mut writer, &resp, false);
+                let _ = writer.flush();
+                return Err(error);
+            },
+            Err(error) => return Err(error),
+        };
+        let resp = route(engine, &session, &req);
+        write_response(&mut writer, &resp, req.keep_alive)?;
+        if !req.keep_alive {
+            break;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+    token: Option<String>,
+    keep_alive: bool,
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn empty(status: u16) -> Response {
+        Response{status, content_type: "text/plain", body: Vec::new()}
+    }
+
+    fn json(status: u16, body: String) -> Response {
+        Response{status, content_type: "application/json", body: body.into_bytes()}
+    }
+}
+
+fn route<E: KvsEngine>(engine: &E, session: &Session, req: &Request) -> Response {
+    let (path, query) = req.path.split_once('?').unwrap_or((&req.path, ""));
+    let session = match path {
+        "/health" => session.clone(),
+        _ => match login(session, req) {
+            Ok(session) => session,
+            Err(error) => return error_response(error.into()),
+        },
+    };
+    let res = match (req.method.as_str(), path) {
+        ("GET", "/health") => Ok(Response::json(200, r#"{"status":"ok"}"#.to_string())),
+        ("GET", "/keys") => scan_route(engine, &session, query),
+        (method, path) if path.starts_with("/keys/") => {
+            match percent_decode(&path["/keys/".len()..]) {
+                Ok(key) => key_route(engine, &session, method, key, &req.body),
+                Err(error) => Err(error),
+            }
+        },
+        (_, "/health") | (_, "/keys") => Ok(Response::empty(405)),
+        _ => Ok(Response::empty(404)),
+    };
+    res.unwrap_or_else(|error| error_response(error.into()))
+}
+
+fn login(session: &Session, req: &Request) -> Result<Session> {
+    let mut session = session.clone();
+    if let Some(token) = &req.token {
+        session.login(token)?;
+    }
+    session.check()?;
+    Ok(session)
+}
+
+fn key_route<E: KvsEngine>(engine: &E, session: &Session, method: &str, key: Vec<u8>, body: &[u8]) -> Result<Response> {
+    match method {
+        "GET" => {
+            session.authorize(Operation::Read, &key)?;
+            Ok(match engine.get(&key)? {
+                Some(value) => Response{status: 200, content_type: "application/octet-stream", body: value},
+                None => error_response(KvsError::KeyNotFound.into()),
+            })
+        },
+        "PUT" => {
+            session.authorize(Operation::Write, &key)?;
+            engine.set(key, body.to_vec()).map(|()| Response::empty(204))
+        },
+        "DELETE" => {
+            session.authorize(Operation::Delete, &key)?;
+            engine.remove(&key).map(|()| Response::empty(204))
+        },
+        _ => Ok(Response::empty(405)),
+    }
+}
+
+fn scan_route<E: KvsEngine>(engine: &E, session: &Session, query: &str) -> Result<Response> {
+    let (mut prefix, mut start, mut end, mut limit) = (Vec::new(), None, None, DEFAULT_SCAN_LIMIT);
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        let (name, value) = param.split_once('=').unwrap_or((param, ""));
+        match name {
+            "prefix" => prefix = percent_decode(value)?,
+            "start" => start = Some(percent_decode(value)?),
+            "end" => end = Some(percent_decode(value)?),
+            "limit" => limit = value.parse().map_err(|_| protocol_error("invalid limit"))?,
+            _ => return Err(protocol_error("unknown query parameter")),
+        }
+    }
+    let page = scan_page(engine, session, &prefix, start, end, limit)?;
+    Ok(Response::json(200, serde_json::to_string(&page)?))
+}
+
+fn error_response(error: ResponseError) -> Response {
+    let status = match error.code {
+        ErrorCode::KeyNotFound => 404,
+        ErrorCode::InvalidRequest => 400,
+        ErrorCode::Unauthorized => 401,
+        ErrorCode::Forbidden => 403,
+        ErrorCode::Overloaded => 503,
+        _ => 500,
+    };
+    let body = serde_json::to_string(&error).expect("error serializes");
+    Response::json(status, body)
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+fn write_response<W: Write>(w: &mut W, resp: &Response, keep_alive: bool) -> Result<()> {
+    write!(w, "HTTP/1.1 {} {}\r\n", resp.status, reason(resp.status))?;
+    write!(w, "Content-Type: {}\r\nContent-Length: {}\r\n", resp.content_type, resp.body.len())?;
+    if resp.status == 401 {
+        w.write_all(b"WWW-Authenticate: Bearer\r\n")?;
+    }
+    if !keep_alive {
+        w.write_all(b"Connection: close\r\n")?;
+    }
+    w.write_all(b"\r\n")?;
+    w.write_all(&resp.body)?;
+    Ok(())
+}
+
+// Reads a request with its body. Returns `None` if the connection closes
+// before a request starts.
+fn read_request<R: BufRead>(r: &mut R) -> Result<Option<Request>> {
+    let line = match read_line(r)? {
+        Some(line) => String::from_utf8(line).map_err(|_| protocol_error("request line is not UTF-8"))?,
+        None => return Ok(None),
+    };
+    let mut parts = line.split(' ');
+    let (method, path, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), Some(version), None) => (method, path, version),
+        _ => return Err(protocol_error("malformed request line")),
+    };
+    let mut keep_alive = match version {
+        "HTTP/1.1" => true,
+        "HTTP/1.0" => false,
+        _ => return Err(protocol_error("unsupported HTTP version")),
+    };
+    let mut content_length = 0;
+    let mut token = None;
+    for n in 0.. {
+        let line = read_line(r)?.ok_or_else(unexpected_eof)?;
+        if line.is_empty() {
+            break;
+        }
+        if n == MAX_HEADERS {
+            return Err(protocol_error("too many headers"));
+        }
+        let line = String::from_utf8_lossy(&line);
+        let (name, value) = line.split_once(':').ok_or_else(|| protocol_error("malformed header"))?;
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => {
+                content_length = value.parse::<usize>().map_err(|_| protocol_error("invalid Content-Length"))?;
+                if content_length > MAX_FRAME_SIZE {
+                    return Err(KvsError::FrameTooLarge(content_length as u64));
+                }
+            },
+            "authorization" => {
+                token = value.split_once(' ')
+                    .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+                    .map(|(_, token)| token.trim().to_string());
+            },
+            "transfer-encoding" => return Err(protocol_error("chunked bodies are not supported")),
+            "connection" if value.eq_ignore_ascii_case("close") => keep_alive = false,
+            "connection" if value.eq_ignore_ascii_case("keep-alive") => keep_alive = true,
+            _ => {},
+        }
+    }
+    let body = read_payload(r, content_length)?;
+    Ok(Some(Request{method: method.to_string(), path: path.to_string(), body, token, keep_alive}))
+}
+
+fn percent_decode(s: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let byte = tail.get(..2)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| protocol_error("invalid percent-encoding"))?;
+            bytes.push(byte);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    Ok(bytes)
+}
+
+fn protocol_error(msg: &str) -> KvsError {
+    KvsError::Protocol(msg.to_string())
+}
@@ -1,6 +1,21 @@
 pub use error::{KvsError, Result};
 mod error;
-pub use engines::{KvsEngine, KvStore,Sled};
+pub use engines::{KvsEngine, KvStore, ScanIter, Sled, WriteBatch};
 mod engines;
-pub use server::{KvsServer, Req, GetResponse, SetResponse, RemoveResponse};
-mod server;
\ No newline at end of file
+pub use server::{KvsServer, ShutdownHandle};
+mod server;
+pub use auth::Authenticator;
+mod auth;
+pub use acl::Policy;
+mod acl;
+pub use protocol::{Request, Req, Response, Reply, Page, KeyValue, WireBatch, WireBatchOp, ResponseError, ErrorCode, Hello, HelloResponse, MAX_FRAME_SIZE, PROTOCOL_VERSION, MIN_PROTOCOL_VERSION};
+mod protocol;
+mod resp;
+mod http;
+mod net;
+pub use client::{KvsClient, RemoteScan};
+mod client;
+pub use asynchronous::{AsyncKvsClient, AsyncKvsServer};
+mod asynchronous;
+pub mod thread_pool;
+pub mod tls;
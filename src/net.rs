@@ -0,0 +1,282 @@
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use rustls::{Connection, ServerConfig, ServerConnection};
+#[cfg(unix)]
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use log::warn;
+
+use crate::Result;
+#[cfg(unix)]
+use crate::KvsError;
+
+// A connection over TCP, TLS over TCP, or a Unix domain socket.
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    Tls(Arc<TlsStream>),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+// A TLS session over a TCP socket. Reads and writes go through a lock on the
+// session, so, as for the other streams, a clone can read while another
+// writes. Only one side is ever blocked in the socket at a time, which suits
+// request/response traffic.
+pub(crate) struct TlsStream {
+    sock: TcpStream,
+    conn: Mutex<Connection>,
+}
+
+impl TlsStream {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        loop {
+            match conn.reader().read(buf) {
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => {},
+                res => return res,
+            }
+            // handshake messages may need to go out before anything more
+            // can come in
+            while conn.wants_write() {
+                conn.write_tls(&mut &self.sock)?;
+            }
+            conn.read_tls(&mut &self.sock)?;
+            conn.process_new_packets().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let written = conn.writer().write(buf)?;
+        while conn.wants_write() {
+            conn.write_tls(&mut &self.sock)?;
+        }
+        Ok(written)
+    }
+}
+
+impl Drop for TlsStream {
+    fn drop(&mut self) {
+        let conn = self.conn.get_mut().unwrap();
+        conn.send_close_notify();
+        while conn.wants_write() {
+            if conn.write_tls(&mut &self.sock).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+impl Stream {
+    pub(crate) fn tls<C: Into<Connection>>(sock: TcpStream, conn: C) -> Stream {
+        Stream::Tls(Arc::new(TlsStream{sock, conn: Mutex::new(conn.into())}))
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_read_timeout(timeout),
+            Stream::Tls(s) => s.sock.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.set_read_timeout(timeout),
+        }
+    }
+
+    pub(crate) fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(s) => s.try_clone().map(Stream::Tcp),
+            Stream::Tls(s) => Ok(Stream::Tls(Arc::clone(s))),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.try_clone().map(Stream::Unix),
+        }
+    }
+
+    pub(crate) fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.shutdown(how),
+            Stream::Tls(s) => s.sock.shutdown(how),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.shutdown(how),
+        }
+    }
+}
+
+impl Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => (&*s).read(buf),
+            Stream::Tls(s) => s.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => (&*s).read(buf),
+        }
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => (&*s).write(buf),
+            Stream::Tls(s) => s.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => (&*s).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => (&*s).flush(),
+            // writes are handed to the socket as they are made
+            Stream::Tls(_) => Ok(()),
+            #[cfg(unix)]
+            Stream::Unix(s) => (&*s).flush(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+// A listener. A Unix socket's file is removed when its listener is dropped.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    Tls(TcpListener, Arc<ServerConfig>),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    pub(crate) fn bind_tcp(addr: SocketAddr) -> Result<Listener> {
+        Ok(Listener::Tcp(TcpListener::bind(addr)?))
+    }
+
+    pub(crate) fn bind_tls(addr: SocketAddr, config: Arc<ServerConfig>) -> Result<Listener> {
+        Ok(Listener::Tls(TcpListener::bind(addr)?, config))
+    }
+
+    // Binds a socket at `path` that only users allowed by `mode` can connect
+    // to. A socket left behind by a server that is no longer running is
+    // replaced, but one still in use, or any other kind of file, is not.
+    #[cfg(unix)]
+    pub(crate) fn bind_unix(path: &Path, mode: u32) -> Result<Listener> {
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(KvsError::StringError(format!("{} exists and is not a socket", path.display())));
+            }
+            if UnixStream::connect(path).is_ok() {
+                return Err(KvsError::StringError(format!("{} is in use by another server", path.display())));
+            }
+            warn!("removing stale socket {}", path.display());
+            fs::remove_file(path)?;
+        }
+        // the socket is created with the umask's permissions, so it is bound
+        // in a directory only we can enter and moved into place once it has
+        // its final mode
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let name = path.file_name().ok_or_else(|| KvsError::StringError(format!("{} is not a file path", path.display())))?;
+        let private = parent.join(format!(".{}.{}", name.to_string_lossy(), std::process::id()));
+        fs::DirBuilder::new().mode(0o700).create(&private)?;
+        let bound = (|| -> Result<UnixListener> {
+            let tmp = private.join("socket");
+            let listener = UnixListener::bind(&tmp)?;
+            fs::set_permissions(&tmp, fs::Permissions::from_mode(mode))?;
+            fs::rename(&tmp, path)?;
+            Ok(listener)
+        })();
+        if let Err(e) = fs::remove_dir_all(&private) {
+            warn!("failed to remove {}: {}", private.display(), e);
+        }
+        Ok(Listener::Unix(bound?, path.to_path_buf()))
+    }
+
+    // Waits for a connection, returning it and a description of the peer for
+    // logs.
+    pub(crate) fn accept(&self) -> io::Result<(Stream, String)> {
+        let (stream, peer) = match self {
+            Listener::Tcp(listener) => {
+                let (stream, peer) = listener.accept()?;
+                (Stream::Tcp(stream), peer.to_string())
+            },
+            // the handshake happens on the first read, off the accept loop
+            Listener::Tls(listener, config) => {
+                let (stream, peer) = listener.accept()?;
+                let conn = ServerConnection::new(Arc::clone(config))
+                    .map_err(|e| io::Error::other(e.to_string()))?;
+                (Stream::tls(stream, conn), peer.to_string())
+            },
+            #[cfg(unix)]
+            Listener::Unix(listener, path) => {
+                let (stream, _) = listener.accept()?;
+                (Stream::Unix(stream), path.display().to_string())
+            },
+        };
+        Ok((stream, peer))
+    }
+
+    // Where to connect to wake up an `accept`.
+    pub(crate) fn waker(&self) -> io::Result<Waker> {
+        match self {
+            Listener::Tcp(listener) | Listener::Tls(listener, _) => {
+                let mut addr = listener.local_addr()?;
+                if addr.ip().is_unspecified() {
+                    addr.set_ip(match addr {
+                        SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                        SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                    });
+                }
+                Ok(Waker::Tcp(addr))
+            },
+            #[cfg(unix)]
+            Listener::Unix(_, path) => Ok(Waker::Unix(path.clone())),
+        }
+    }
+}
+
+// Wakes up a listener blocked in `accept` by connecting to it.
+#[derive(Clone, Debug)]
+pub(crate) enum Waker {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Waker {
+    pub(crate) fn wake(&self) -> io::Result<()> {
+        match self {
+            Waker::Tcp(addr) => TcpStream::connect(addr).map(drop),
+            #[cfg(unix)]
+            Waker::Unix(path) => UnixStream::connect(path).map(drop),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
@@ -0,0 +1,444 @@
+use std::io::{self, Read, Write};
+use std::time::Duration;
+use serde::{de::DeserializeOwned, Serialize, Deserialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::engines::BatchOp;
+use crate::{KvsError, Result, WriteBatch};
+
+// Every message on the wire is a frame: its length as a big endian u32
+// followed by that many bytes of payload. Payloads are JSON encoded, but
+// framing does not depend on that.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+// How long the rest of a frame may take to arrive once it has started.
+pub(crate) const FRAME_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) fn write_frame<W: Write>(w: &mut W, payload: &[u8]) -> Result<()> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(KvsError::FrameTooLarge(payload.len() as u64));
+    }
+    w.write_all(&(payload.len() as u32).to_be_bytes())?;
+    w.write_all(payload)?;
+    Ok(())
+}
+
+// Returns `None` if the stream ends cleanly before a new frame.
+pub(crate) fn read_frame<R: Read>(r: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut header = [0; 4];
+    match r.read_exact(&mut header[..1]) {
+        Ok(()) => {},
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(KvsError::from(error)),
+    }
+    r.read_exact(&mut header[1..])?;
+    let len = check_frame_len(header)?;
+    Ok(Some(read_payload(r, len)?))
+}
+
+// Reads exactly `len` bytes. The buffer grows as they arrive rather than being
+// allocated up front, so a peer cannot claim a large payload for free.
+pub(crate) fn read_payload<R: Read>(r: &mut R, len: usize) -> io::Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    r.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    Ok(payload)
+}
+
+pub(crate) async fn write_frame_async<W: AsyncWrite + Unpin>(w: &mut W, payload: &[u8]) -> Result<()> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(KvsError::FrameTooLarge(payload.len() as u64));
+    }
+    let mut buf = Vec::with_capacity(4 + payload.len());
+    buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    buf.extend_from_slice(payload);
+    w.write_all(&buf).await?;
+    Ok(())
+}
+
+// Like `read_frame`, but a frame that has started must be complete within
+// `FRAME_READ_TIMEOUT`.
+pub(crate) async fn read_frame_async<R: AsyncRead + Unpin>(r: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut header = [0; 4];
+    match r.read_exact(&mut header[..1]).await {
+        Ok(_) => {},
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(KvsError::from(error)),
+    }
+    let rest = async {
+        r.read_exact(&mut header[1..]).await?;
+        let len = check_frame_len(header)?;
+        let mut payload = Vec::new();
+        (&mut *r).take(len as u64).read_to_end(&mut payload).await?;
+        if payload.len() < len {
+            return Err(KvsError::from(io::Error::from(io::ErrorKind::UnexpectedEof)));
+        }
+        Ok(payload)
+    };
+    match tokio::time::timeout(FRAME_READ_TIMEOUT, rest).await {
+        Ok(res) => res.map(Some),
+        Err(_) => Err(KvsError::from(io::Error::from(io::ErrorKind::TimedOut))),
+    }
+}
+
+fn check_frame_len(header: [u8; 4]) -> Result<usize> {
+    let len = u32::from_be_bytes(header) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(KvsError::FrameTooLarge(len as u64));
+    }
+    Ok(len)
+}
+
+pub(crate) fn send<T: Serialize, W: Write>(w: &mut W, msg: &T) -> Result<()> {
+    queue(w, msg)?;
+    w.flush()?;
+    Ok(())
+}
+
+// Like `send`, but leaves flushing to the caller so that several messages can
+// go out together.
+pub(crate) fn queue<T: Serialize, W: Write>(w: &mut W, msg: &T) -> Result<()> {
+    write_frame(w, &serde_json::to_vec(msg)?)
+}
+
+pub(crate) fn receive<T: DeserializeOwned, R: Read>(r: &mut R) -> Result<Option<T>> {
+    match read_frame(r)? {
+        Some(payload) => Ok(Some(serde_json::from_slice(&payload)?)),
+        None => Ok(None),
+    }
+}
+
+pub(crate) async fn send_async<T: Serialize, W: AsyncWrite + Unpin>(w: &mut W, msg: &T) -> Result<()> {
+    queue_async(w, msg).await?;
+    w.flush().await?;
+    Ok(())
+}
+
+pub(crate) async fn queue_async<T: Serialize, W: AsyncWrite + Unpin>(w: &mut W, msg: &T) -> Result<()> {
+    write_frame_async(w, &serde_json::to_vec(msg)?).await
+}
+
+pub(crate) async fn receive_async<T: DeserializeOwned, R: AsyncRead + Unpin>(r: &mut R) -> Result<Option<T>> {
+    match read_frame_async(r).await? {
+        Some(payload) => Ok(Some(serde_json::from_slice(&payload)?)),
+        None => Ok(None),
+    }
+}
+
+// The newest and oldest protocol versions this build speaks. Bump
+// `PROTOCOL_VERSION` for any change to the messages below, and raise
+// `MIN_PROTOCOL_VERSION` only once older peers are no longer supported.
+// Version 3 tagged requests with ids. Every version since has only added
+// requests (see `Req::version`) and error codes (see `ErrorCode::for_version`),
+// which are not sent to peers that agreed on an older version.
+pub const PROTOCOL_VERSION: u32 = 8;
+pub const MIN_PROTOCOL_VERSION: u32 = 3;
+
+// Optional features this build supports, advertised in the handshake.
+pub(crate) const CAPABILITIES: &[&str] = &[];
+
+// The first message on every connection, sent by the client. The server
+// answers with a `HelloResponse` holding the version both sides will use and
+// the capabilities they share, or an error if their ranges do not overlap.
+// These two messages must stay the same across protocol versions.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hello {
+    pub version: u32,
+    pub min_version: u32,
+    pub capabilities: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum HelloResponse {
+    Ok(Hello),
+    Err(String),
+}
+
+impl Hello {
+    // What this build advertises.
+    pub fn local() -> Hello {
+        Hello {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    // Run by the server on the client's hello: picks the newest version both
+    // sides speak.
+    pub(crate) fn negotiate(&self, peer: &Hello) -> std::result::Result<Hello, String> {
+        let version = self.version.min(peer.version);
+        if version < self.min_version.max(peer.min_version) {
+            return Err(format!(
+                "incompatible protocol versions: server speaks {} to {}, client speaks {} to {}",
+                self.min_version, self.version, peer.min_version, peer.version
+            ));
+        }
+        let capabilities = self.capabilities.iter()
+            .filter(|c| peer.capabilities.contains(c))
+            .cloned()
+            .collect();
+        Ok(Hello{version, min_version: version, capabilities})
+    }
+}
+
+// A request tagged with an id chosen by the client. Clients may send several
+// requests before reading any responses; each response carries the id of the
+// request it answers.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Request {
+    pub id: u64,
+    pub req: Req,
+}
+
+// Keys and values are arbitrary bytes, carried as base64 strings in the JSON.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Req{
+    Set {
+        #[serde(with = "base64_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "base64_bytes")]
+        value: Vec<u8>,
+    },
+    // a `Set` of a key that expires after `ttl_ms` milliseconds
+    SetWithTtl {
+        #[serde(with = "base64_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "base64_bytes")]
+        value: Vec<u8>,
+        ttl_ms: u64,
+    },
+    Remove {
+        #[serde(with = "base64_bytes")]
+        key: Vec<u8>,
+    },
+    Get {
+        #[serde(with = "base64_bytes")]
+        key: Vec<u8>,
+    },
+    // Writes applied all together or not at all, see `KvsEngine::apply_batch`
+    Batch(WireBatch),
+    // One page of the keys starting with `prefix`, from `start` (inclusive)
+    // to `end` (exclusive), and their values in key order. At most `limit`
+    // entries are returned; the page says where the next one starts.
+    Scan {
+        #[serde(with = "base64_bytes")]
+        prefix: Vec<u8>,
+        #[serde(with = "base64_bytes::option")]
+        start: Option<Vec<u8>>,
+        #[serde(with = "base64_bytes::option")]
+        end: Option<Vec<u8>>,
+        limit: u32,
+    },
+    // Identifies the client for the rest of the connection. Servers that
+    // require authentication refuse other requests until this succeeds.
+    Auth {
+        token: String,
+    },
+}
+
+// A `WriteBatch` as it is carried in a `Req::Batch`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WireBatch {
+    pub ops: Vec<WireBatchOp>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum WireBatchOp {
+    Set {
+        #[serde(with = "base64_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "base64_bytes")]
+        value: Vec<u8>,
+    },
+    Remove {
+        #[serde(with = "base64_bytes")]
+        key: Vec<u8>,
+    },
+}
+
+impl From<WriteBatch> for WireBatch {
+    fn from(batch: WriteBatch) -> WireBatch {
+        let ops = batch.ops.into_iter().map(|op| match op {
+            BatchOp::Set{key, value} => WireBatchOp::Set{key, value},
+            BatchOp::Remove{key} => WireBatchOp::Remove{key},
+        }).collect();
+        WireBatch{ops}
+    }
+}
+
+impl From<WireBatch> for WriteBatch {
+    fn from(batch: WireBatch) -> WriteBatch {
+        let ops = batch.ops.into_iter().map(|op| match op {
+            WireBatchOp::Set{key, value} => BatchOp::Set{key, value},
+            WireBatchOp::Remove{key} => BatchOp::Remove{key},
+        }).collect();
+        WriteBatch{ops}
+    }
+}
+
+impl Req {
+    // The protocol version that added the request.
+    pub(crate) fn version(&self) -> u32 {
+        match self {
+            Req::Set { .. } | Req::Remove { .. } | Req::Get { .. } => MIN_PROTOCOL_VERSION,
+            Req::Auth { .. } => 4,
+            Req::SetWithTtl { .. } => 6,
+            Req::Scan { .. } => 7,
+            Req::Batch(_) => 8,
+        }
+    }
+
+    // Fails if the request is newer than the protocol version agreed on for
+    // the connection.
+    pub(crate) fn check_version(&self, version: u32) -> Result<()> {
+        if self.version() > version {
+            return Err(KvsError::Protocol(format!(
+                "request needs protocol version {}, but the connection uses {}",
+                self.version(), version
+            )));
+        }
+        Ok(())
+    }
+}
+
+// The answer to a request. `id` is the id of the request it answers, or 0 if
+// the request could not be read.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Response {
+    pub id: u64,
+    pub result: std::result::Result<Reply, ResponseError>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub enum Reply {
+    // the value of a `Get`
+    Value(#[serde(with = "base64_bytes::option")] Option<Vec<u8>>),
+    // the entries found by a `Scan`
+    Page(Page),
+    // any other request succeeded
+    Done,
+}
+
+// Entries in key order. `next` is the key to start the following page from,
+// if the scan did not reach the end of its range. Readers may skip keys they
+// are not allowed to see, so a page can be short, or even empty, and still
+// have a next one.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Page {
+    pub entries: Vec<KeyValue>,
+    #[serde(with = "base64_bytes::option")]
+    pub next: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct KeyValue {
+    #[serde(with = "base64_bytes")]
+    pub key: Vec<u8>,
+    #[serde(with = "base64_bytes")]
+    pub value: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+// What went wrong on the server, for clients to branch on. The message only
+// explains it to a person.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    KeyNotFound,
+    Io,
+    Corruption,
+    InvalidRequest,
+    Unauthorized,
+    Forbidden,
+    Overloaded,
+    Internal,
+}
+
+impl ErrorCode {
+    // Replaces a code added after `version` with the closest one a peer on
+    // that version knows.
+    pub(crate) fn for_version(self, version: u32) -> ErrorCode {
+        match self {
+            ErrorCode::Forbidden if version < 5 => ErrorCode::Unauthorized,
+            code => code,
+        }
+    }
+}
+
+impl From<&KvsError> for ErrorCode {
+    fn from(error: &KvsError) -> ErrorCode {
+        match error {
+            KvsError::KeyNotFound => ErrorCode::KeyNotFound,
+            KvsError::Io(_) => ErrorCode::Io,
+            KvsError::Corruption { .. } | KvsError::UnexpectedEntry => ErrorCode::Corruption,
+            KvsError::Serde(_) | KvsError::FrameTooLarge(_) | KvsError::Protocol(_) => ErrorCode::InvalidRequest,
+            KvsError::Unauthorized(_) => ErrorCode::Unauthorized,
+            KvsError::Forbidden(_) => ErrorCode::Forbidden,
+            KvsError::Remote { code, .. } => *code,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
+impl From<KvsError> for ResponseError {
+    fn from(error: KvsError) -> ResponseError {
+        ResponseError{code: ErrorCode::from(&error), message: error.to_string()}
+    }
+}
+
+impl From<ResponseError> for KvsError {
+    fn from(error: ResponseError) -> KvsError {
+        match error.code {
+            ErrorCode::KeyNotFound => KvsError::KeyNotFound,
+            ErrorCode::Io => KvsError::Io(io::Error::other(error.message)),
+            ErrorCode::Unauthorized => KvsError::Unauthorized(error.message),
+            ErrorCode::Forbidden => KvsError::Forbidden(error.message),
+            code => KvsError::Remote{code, message: error.message},
+        }
+    }
+}
+
+// Whether a receive failed because the peer sent something unreadable, rather
+// than because the connection broke.
+pub(crate) fn is_invalid_input(error: &KvsError) -> bool {
+    matches!(error, KvsError::Serde(_) | KvsError::FrameTooLarge(_))
+}
+
+mod base64_bytes {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(d)?;
+        STANDARD.decode(s).map_err(D::Error::custom)
+    }
+
+    pub mod option {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(bytes: &Option<Vec<u8>>, s: S) -> Result<S::Ok, S::Error> {
+            match bytes {
+                Some(bytes) => s.serialize_some(&STANDARD.encode(bytes)),
+                None => s.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<u8>>, D::Error> {
+            match Option::<String>::deserialize(d)? {
+                Some(s) => STANDARD.decode(s).map(Some).map_err(D::Error::custom),
+                None => Ok(None),
+            }
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,292 @@
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::time::Duration;
+
+use crate::acl::Operation;
+use crate::auth::Session;
+use crate::protocol::{read_payload, ErrorCode, MAX_FRAME_SIZE, PROTOCOL_VERSION};
+use crate::net::Stream;
+use crate::server::wait_for_message;
+use crate::{KvsEngine, KvsError, Result, WriteBatch};
+
+// Limits on what a client may send, in the spirit of Redis' own.
+const MAX_LINE: usize = 64 * 1024;
+const MAX_ARGS: usize = 1024 * 1024;
+
+// A RESP2 reply.
+#[derive(Debug)]
+enum Value {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Value>),
+}
+
+// Serves a connection speaking RESP2, the Redis protocol. Only the handful of
+// commands in `execute` are understood; they map directly onto the engine.
+// When the server requires authentication, clients must send AUTH first.
+pub(crate) fn serve<E: KvsEngine>(engine: &E, ts: Stream, mut session: Session) -> Result<()> {
+    let mut reader = BufReader::new(&ts);
+    let mut writer = BufWriter::new(&ts);
+    loop {
+        // replies to pipelined commands go out together
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+        if !wait_for_message(&ts, &mut reader)? {
+            break;
+        }
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            Err(error @ KvsError::Protocol(_)) => {
+                let _ = write_value(&mut writer, &Value::Error(format!("ERR {}", error)));
+                let _ = writer.flush();
+                return Err(error);
+            },
+            Err(error) => return Err(error),
+        };
+        if args.is_empty() {
+            continue;
+        }
+        let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+        write_value(&mut writer, &authorize(engine, &mut session, args))?;
+        if quit {
+            break;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// Handles AUTH, and refuses other commands until it has succeeded. As in
+// Redis 6, AUTH takes either a password, here a token, or a user and token.
+fn authorize<E: KvsEngine>(engine: &E, session: &mut Session, mut args: Vec<Vec<u8>>) -> Value {
+    if args[0].eq_ignore_ascii_case(b"AUTH") {
+        let user = match args.len() {
+            2 => None,
+            3 => Some(args.remove(1)),
+            _ => return Value::Error("ERR wrong number of arguments for 'auth' command".to_string()),
+        };
+        let user = user.map(|user| String::from_utf8_lossy(&user).into_owned());
+        let token = String::from_utf8_lossy(&args[1]);
+        return match session.login_as(user.as_deref(), &token) {
+            Ok(()) => Value::Simple("OK"),
+            Err(_) => Value::Error("WRONGPASS invalid username-password pair".to_string()),
+        };
+    }
+    if !args[0].eq_ignore_ascii_case(b"QUIT") {
+        if let Err(error) = session.check() {
+            return error_value(&error);
+        }
+    }
+    execute(engine, session, args)
+}
+
+// Fails unless the session may run the command on every key it names, so
+// that a command is either refused or run in full.
+fn authorize_keys<'k>(session: &Session, op: Operation, keys: impl IntoIterator<Item = &'k Vec<u8>>) -> Result<()> {
+    keys.into_iter().try_for_each(|key| session.authorize(op, key))
+}
+
+// Every command that touches keys checks them with `authorize_keys` first.
+fn execute<E: KvsEngine>(engine: &E, session: &Session, mut args: Vec<Vec<u8>>) -> Value {
+    let name = String::from_utf8_lossy(&args.remove(0)).to_ascii_uppercase();
+    let res = match (name.as_str(), args.len()) {
+        ("PING", 0) => Ok(Value::Simple("PONG")),
+        ("PING", 1) => Ok(Value::Bulk(args.pop())),
+        ("QUIT", 0) => Ok(Value::Simple("OK")),
+        ("GET", 1) => authorize_keys(session, Operation::Read, &args).and_then(|()| engine.get(&args[0]).map(Value::Bulk)),
+        ("SET", 2) => authorize_keys(session, Operation::Write, &args[..1]).and_then(|()| {
+            let value = args.pop().unwrap();
+            let key = args.pop().unwrap();
+            engine.set(key, value).map(|()| Value::Simple("OK"))
+        }),
+        ("SET", 4) => match authorize_keys(session, Operation::Write, &args[..1]) {
+            Ok(()) => return set_expiring(engine, args),
+            Err(error) => Err(error),
+        },
+        ("DEL", n) if n > 0 => authorize_keys(session, Operation::Delete, &args).and_then(|()| delete(engine, &args)),
+        ("EXISTS", n) if n > 0 => authorize_keys(session, Operation::Read, &args).and_then(|()| exists(engine, &args)),
+        ("MGET", n) if n > 0 => authorize_keys(session, Operation::Read, &args).and_then(|()| {
+            args.iter()
+                .map(|key| engine.get(key).map(Value::Bulk))
+                .collect::<Result<Vec<_>>>()
+                .map(Value::Array)
+        }),
+        // every other argument is a value
+        ("MSET", n) if n > 0 && n % 2 == 0 => {
+            authorize_keys(session, Operation::Write, args.iter().step_by(2)).and_then(|()| mset(engine, args))
+        },
+        ("INFO", 0) | ("INFO", 1) => Ok(Value::Bulk(Some(info().into_bytes()))),
+        // redis-cli asks for command docs on startup and copes with none
+        ("COMMAND", _) => Ok(Value::Array(Vec::new())),
+        ("PING" | "QUIT" | "GET" | "SET" | "DEL" | "EXISTS" | "MGET" | "MSET" | "INFO", _) => {
+            return Value::Error(format!("ERR wrong number of arguments for '{}' command", name.to_ascii_lowercase()));
+        },
+        _ => return Value::Error(format!("ERR unknown command '{}'", name)),
+    };
+    res.unwrap_or_else(|error| error_value(&error))
+}
+
+// SET key value EX seconds, or PX milliseconds.
+fn set_expiring<E: KvsEngine>(engine: &E, mut args: Vec<Vec<u8>>) -> Value {
+    let amount = std::str::from_utf8(&args[3]).ok().and_then(|n| n.parse::<u64>().ok()).filter(|n| *n > 0);
+    let ttl = match (args[2].to_ascii_uppercase().as_slice(), amount) {
+        (b"EX", Some(secs)) => Duration::from_secs(secs),
+        (b"PX", Some(millis)) => Duration::from_millis(millis),
+        (b"EX" | b"PX", None) => return Value::Error("ERR invalid expire time in 'set' command".to_string()),
+        _ => return Value::Error("ERR syntax error".to_string()),
+    };
+    args.truncate(2);
+    let value = args.pop().unwrap();
+    let key = args.pop().unwrap();
+    match engine.set_with_ttl(key, value, ttl) {
+        Ok(()) => Value::Simple("OK"),
+        Err(error) => error_value(&error),
+    }
+}
+
+// Counts the keys that existed.
+fn delete<E: KvsEngine>(engine: &E, keys: &[Vec<u8>]) -> Result<Value> {
+    let mut removed = 0;
+    for key in keys {
+        match engine.remove(key) {
+            Ok(()) => removed += 1,
+            Err(KvsError::KeyNotFound) => {},
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(Value::Integer(removed))
+}
+
+fn exists<E: KvsEngine>(engine: &E, keys: &[Vec<u8>]) -> Result<Value> {
+    let mut found = 0;
+    for key in keys {
+        if engine.get(key)?.is_some() {
+            found += 1;
+        }
+    }
+    Ok(Value::Integer(found))
+}
+
+// As in Redis, the keys are all set at once.
+fn mset<E: KvsEngine>(engine: &E, args: Vec<Vec<u8>>) -> Result<Value> {
+    let mut batch = WriteBatch::new();
+    let mut args = args.into_iter();
+    while let (Some(key), Some(value)) = (args.next(), args.next()) {
+        batch.set(key, value);
+    }
+    engine.apply_batch(batch)?;
+    Ok(Value::Simple("OK"))
+}
+
+fn info() -> String {
+    format!(
+        "# Server\r\nkvs_version:{}\r\nkvs_protocol_version:{}\r\n",
+        env!("CARGO_PKG_VERSION"),
+        PROTOCOL_VERSION
+    )
+}
+
+fn error_value(error: &KvsError) -> Value {
+    let prefix = match ErrorCode::from(error) {
+        ErrorCode::Unauthorized => "NOAUTH",
+        ErrorCode::Forbidden => "NOPERM",
+        _ => "ERR",
+    };
+    Value::Error(format!("{} {}", prefix, error))
+}
+
+// Reads the arguments of the next command, either a RESP array of bulk
+// strings or an inline command as typed into telnet. Returns `None` if the
+// connection closes before a command starts.
+fn read_command<R: BufRead>(r: &mut R) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(r)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        let args = line.split(|b| b.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some(args));
+    }
+    let count = parse_len(&line[1..], MAX_ARGS)?;
+    let mut args = Vec::with_capacity(count.min(64));
+    // what a command may hold in all, so a client only gets memory for the
+    // bytes it actually sends
+    let mut left = MAX_FRAME_SIZE;
+    for _ in 0..count {
+        let line = read_line(r)?.ok_or_else(unexpected_eof)?;
+        if line.first() != Some(&b'$') {
+            return Err(KvsError::Protocol(format!("expected '$', got '{}'", String::from_utf8_lossy(&line))));
+        }
+        let len = parse_len(&line[1..], MAX_FRAME_SIZE)?;
+        left = left.checked_sub(len)
+            .ok_or_else(|| KvsError::Protocol(format!("command larger than {} bytes", MAX_FRAME_SIZE)))?;
+        let arg = read_payload(r, len)?;
+        let mut crlf = [0; 2];
+        r.read_exact(&mut crlf)?;
+        if &crlf != b"\r\n" {
+            return Err(KvsError::Protocol("bulk string not followed by CRLF".to_string()));
+        }
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+// Reads a line without its line ending. Also used for HTTP.
+pub(crate) fn read_line<R: BufRead>(r: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    r.take(MAX_LINE as u64 + 1).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        if line.len() >= MAX_LINE {
+            return Err(KvsError::Protocol("line too long".to_string()));
+        }
+        return Err(unexpected_eof());
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8], max: usize) -> Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse::<i64>().ok())
+        .filter(|len| (0..=max as i64).contains(len))
+        .map(|len| len as usize)
+        .ok_or_else(|| KvsError::Protocol(format!("invalid length '{}'", String::from_utf8_lossy(digits))))
+}
+
+pub(crate) fn unexpected_eof() -> KvsError {
+    KvsError::from(io::Error::from(io::ErrorKind::UnexpectedEof))
+}
+
+fn write_value<W: Write>(w: &mut W, value: &Value) -> Result<()> {
+    match value {
+        Value::Simple(s) => write!(w, "+{}\r\n", s)?,
+        // a line break would end the reply early
+        Value::Error(msg) => write!(w, "-{}\r\n", msg.replace(['\r', '\n'], " "))?,
+        Value::Integer(n) => write!(w, ":{}\r\n", n)?,
+        Value::Bulk(None) => w.write_all(b"$-1\r\n")?,
+        Value::Bulk(Some(bytes)) => {
+            write!(w, "${}\r\n", bytes.len())?;
+            w.write_all(bytes)?;
+            w.write_all(b"\r\n")?;
+        },
+        Value::Array(values) => {
+            write!(w, "*{}\r\n", values.len())?;
+            for value in values {
+                write_value(w, value)?;
+            }
+        },
+    }
+    Ok(())
+}
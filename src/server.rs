@@ -1,106 +1,506 @@
-use core::fmt;
-use std::{io::{Read, Write}, net::{SocketAddr, TcpListener}, str::Bytes};
-use std::io::{BufReader, BufWriter};
-use serde_json::{Deserializer,Serializer};
-use serde::{Serialize, Deserialize};
-use log::{info};
-use std::net::TcpStream;
+use std::net::SocketAddr;
+use std::ops::Bound;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::net::Shutdown;
+#[cfg(unix)]
+use std::path::PathBuf;
+use log::{debug, error, info, warn};
+use rustls::ServerConfig;
+use serde::de::DeserializeOwned;
 
 use crate::{KvsEngine, Result, KvsError};
+use crate::acl::{Operation, Policy};
+use crate::auth::{Authenticator, Session};
+use crate::engines::prefix_range;
+use crate::protocol::{self, ErrorCode, Hello, HelloResponse, KeyValue, Page, Reply, Req, Request, Response, ResponseError, WireBatchOp, FRAME_READ_TIMEOUT, MAX_FRAME_SIZE};
+use crate::net::{Listener, Stream, Waker};
+use crate::{http, resp};
+use crate::thread_pool::ThreadPool;
 
-pub struct KvsServer<E: KvsEngine>{
+// How long a listener waits after a failed accept, such as when the process
+// is out of file descriptors, before trying again.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(50);
+
+// Most entries a scan returns in one page.
+const MAX_SCAN_LIMIT: u32 = 1000;
+
+// Past this many bytes of keys and values, a page ends early so that it fits
+// in a frame once encoded.
+const MAX_PAGE_BYTES: usize = MAX_FRAME_SIZE / 4;
+
+// Most keys a page looks at, counting the ones left out of it, so that a
+// reader who may see few keys in a range still gets a bounded amount of work
+// done per request.
+const MAX_SCAN_EXAMINED: usize = 10 * MAX_SCAN_LIMIT as usize;
+
+// How long a shutdown waits for in-flight requests before giving up on them.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct KvsServer<E: KvsEngine, P: ThreadPool>{
     engine: E,
+    pool: P,
+    shutdown: ShutdownHandle,
+    addr: Option<SocketAddr>,
+    tls: Option<Arc<ServerConfig>>,
+    auth: Option<Arc<Authenticator>>,
+    policy: Option<Arc<Policy>>,
+    resp_addr: Option<SocketAddr>,
+    http_addr: Option<SocketAddr>,
+    #[cfg(unix)]
+    unix_socket: Option<(PathBuf, u32)>,
+}
+
+// What a listener's connections speak.
+#[derive(Clone, Copy, Debug)]
+enum Protocol {
+    Kvs,
+    Resp,
+    Http,
+}
+
+// Stops a running `KvsServer` from any thread.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>,
+    // the server's listeners, which are blocked in `accept` until something
+    // connects to them
+    wakers: Arc<Mutex<Vec<Waker>>>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        for waker in self.wakers.lock().unwrap().drain(..) {
+            if let Err(error) = waker.wake() {
+                error!("error waking listener {:?}: {}", waker, error);
+            }
+        }
+    }
+
+    fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
 }
 
-impl <E: KvsEngine> KvsServer<E> {
-    pub fn new(engine: E) -> Self {
-        KvsServer{engine}
+impl <E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
+    pub fn new(engine: E, pool: P) -> Self {
+        KvsServer{
+            engine,
+            pool,
+            shutdown: ShutdownHandle::default(),
+            addr: None,
+            tls: None,
+            auth: None,
+            policy: None,
+            resp_addr: None,
+            http_addr: None,
+            #[cfg(unix)]
+            unix_socket: None,
+        }
     }
 
+    // Requires TLS on the kvs protocol's TCP address. The other listeners
+    // are unaffected. See `tls::server_config`.
+    pub fn with_tls(mut self, config: Arc<ServerConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    // Requires clients on every listener to present one of `auth`'s tokens
+    // before making requests.
+    pub fn with_auth(mut self, auth: Arc<Authenticator>) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    // Limits what each authenticated user may do to which keys. Requires
+    // `with_auth`.
+    pub fn with_policy(mut self, policy: Arc<Policy>) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    // Also serves the kvs protocol on a Unix domain socket at `path`, which
+    // only users allowed by the file mode `mode` can connect to.
+    #[cfg(unix)]
+    pub fn with_unix_socket<T: Into<PathBuf>>(mut self, path: T, mode: u32) -> Self {
+        self.unix_socket = Some((path.into(), mode));
+        self
+    }
+
+    // Also serves RESP2, the Redis protocol, on `addr`, so that Redis tools
+    // can be pointed at the same engine.
+    pub fn with_resp(mut self, addr: SocketAddr) -> Self {
+        self.resp_addr = Some(addr);
+        self
+    }
 
+    // Also serves a small HTTP/JSON API on `addr`, see `http.rs`.
+    pub fn with_http(mut self, addr: SocketAddr) -> Self {
+        self.http_addr = Some(addr);
+        self
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    // Serves the kvs protocol on `addr` as well as on any listeners added
+    // with the `with_*` methods. See `serve`.
     pub fn run(mut self, addr: SocketAddr) -> Result<()> {
-        let listener = TcpListener::bind(addr)?;
-        for stream in listener.incoming() {
-            match stream {
-                Ok(ts) => {
-                    self.serve(ts)?;
-                },
-                Err(error) => {
-                    return Err(KvsError::from(error));
-                }
+        self.addr = Some(addr);
+        self.serve()
+    }
+
+    // Serves clients on the listeners added with the `with_*` methods until
+    // shut down through a `ShutdownHandle`. Returns once in-flight requests
+    // have been answered and the engine has been flushed.
+    pub fn serve(self) -> Result<()> {
+        if self.policy.is_some() && self.auth.is_none() {
+            return Err(KvsError::StringError("an access policy needs authentication to identify users".to_string()));
+        }
+        let listeners = self.bind()?;
+        if listeners.is_empty() {
+            return Err(KvsError::StringError("no address to listen on".to_string()));
+        }
+        let wakers = listeners.iter().map(|(listener, _)| listener.waker()).collect::<io::Result<_>>()?;
+        // set before the listeners start, so that a shutdown either finds
+        // them or is seen by them before they first block
+        *self.shutdown.wakers.lock().unwrap() = wakers;
+        let connections = Arc::new(Connections::default());
+        // each listener blocks in its own thread, handing connections to
+        // this one
+        let (tx, rx) = mpsc::channel();
+        thread::scope(|scope| {
+            for (listener, protocol) in listeners {
+                let tx = tx.clone();
+                let shutdown = self.shutdown.clone();
+                scope.spawn(move || accept_loop(listener, protocol, &shutdown, tx));
+            }
+            drop(tx);
+            for (ts, peer, protocol) in rx {
+                self.accept(ts, peer, protocol, &connections);
             }
+        });
+
+        info!("shutting down, waiting for in-flight requests");
+        if !connections.drain(SHUTDOWN_TIMEOUT) {
+            warn!("gave up waiting for in-flight requests after {:?}", SHUTDOWN_TIMEOUT);
         }
+        self.engine.flush()?;
+        info!("server stopped");
         Ok(())
     }
 
-    fn serve(&mut self, ts: TcpStream) -> Result<()> {
-        let br = BufReader::new(&ts);
-        let req_reader = Deserializer::from_reader(br).into_iter::<Req>();
-        for res in req_reader {
-            match res {
-                Ok(req) => {
-                    match req {
-                        Req::Get { key } => {
-                        let res = self.engine.get(key);
-                        match res {
-                            Ok(r) => send_resp(&ts, GetResponse::Ok(r))?,
-                            Err(err) =>  send_resp(&ts, GetResponse::Err(err.to_string()))?
-                        }
-                        },
-                    Req::Set { key, value } => {
-                        let res = self.engine.set(key, value);
-                        match res {
-                            Ok(()) =>  send_resp(&ts, SetResponse::Ok(()))?,
-                            Err(err) => send_resp(&ts, SetResponse::Err(err.to_string()))?,
-                        }
-                    },
-                    Req::Remove { key } => {
-                        let res = self.engine.remove(key);
-                        match res {
-                            Ok(()) => send_resp(&ts, RemoveResponse::Ok(()))?, 
-                            Err(err) => send_resp(&ts, RemoveResponse::Err(err.to_string()))?,
-                        }
-                    }
+    fn bind(&self) -> Result<Vec<(Listener, Protocol)>> {
+        let mut listeners = Vec::new();
+        if let Some(addr) = self.addr {
+            let listener = match &self.tls {
+                Some(config) => {
+                    info!("serving kvs over TLS on {}", addr);
+                    Listener::bind_tls(addr, Arc::clone(config))?
+                },
+                None => {
+                    info!("serving kvs on {}", addr);
+                    Listener::bind_tcp(addr)?
+                },
+            };
+            listeners.push((listener, Protocol::Kvs));
+        }
+        #[cfg(unix)]
+        if let Some((path, mode)) = &self.unix_socket {
+            info!("serving kvs on {} with mode {:o}", path.display(), mode);
+            listeners.push((Listener::bind_unix(path, *mode)?, Protocol::Kvs));
+        }
+        if let Some(resp_addr) = self.resp_addr {
+            info!("serving RESP on {}", resp_addr);
+            listeners.push((Listener::bind_tcp(resp_addr)?, Protocol::Resp));
+        }
+        if let Some(http_addr) = self.http_addr {
+            info!("serving HTTP on {}", http_addr);
+            listeners.push((Listener::bind_tcp(http_addr)?, Protocol::Http));
+        }
+        Ok(listeners)
+    }
+
+    fn accept(&self, ts: Stream, peer: String, protocol: Protocol, connections: &Arc<Connections>) {
+        debug!("accepted {:?} connection from {}", protocol, peer);
+        let registration = match connections.register(&ts) {
+            Ok(registration) => registration,
+            Err(error) => {
+                error!("error setting up connection from {}: {}", peer, error);
+                return;
+            }
+        };
+        let handler = Handler{
+            engine: self.engine.clone(),
+            auth: self.auth.clone(),
+            policy: self.policy.clone(),
+            protocol,
+        };
+        self.pool.spawn(move || {
+            if let Err(e) = handler.serve(ts) {
+                error!("error serving client {}: {}", peer, e);
+            }
+            drop(registration);
+        });
+    }
+}
+
+// Accepts connections on `listener` and sends them to the server until it
+// is shut down. A failed accept is logged and never ends the loop.
+fn accept_loop(listener: Listener, protocol: Protocol, shutdown: &ShutdownHandle, tx: mpsc::Sender<(Stream, String, Protocol)>) {
+    while !shutdown.is_requested() {
+        match listener.accept() {
+            // the connection that woke us up to stop
+            Ok(_) if shutdown.is_requested() => break,
+            Ok((ts, peer)) => {
+                if tx.send((ts, peer, protocol)).is_err() {
+                    break;
                 }
             },
             Err(error) => {
-                return Err(KvsError::from(error));
+                error!("error accepting connection: {}", error);
+                thread::sleep(ACCEPT_ERROR_DELAY);
             }
         }
     }
+}
+
+// The connections currently being served.
+#[derive(Default)]
+struct Connections {
+    streams: Mutex<(u64, HashMap<u64, Stream>)>,
+    closed: Condvar,
+}
+
+// Unregisters its connection when dropped.
+struct Registration {
+    connections: Arc<Connections>,
+    id: u64,
+}
+
+impl Connections {
+    fn register(self: &Arc<Self>, ts: &Stream) -> io::Result<Registration> {
+        let ts = ts.try_clone()?;
+        let mut streams = self.streams.lock().unwrap();
+        let id = streams.0;
+        streams.0 += 1;
+        streams.1.insert(id, ts);
+        Ok(Registration{connections: Arc::clone(self), id})
+    }
+
+    // Stops reading new requests from every connection, lets the requests
+    // already received finish and waits for the connections to close.
+    // Returns false if some were still open when the timeout elapsed.
+    fn drain(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut streams = self.streams.lock().unwrap();
+        for ts in streams.1.values() {
+            let _ = ts.shutdown(Shutdown::Read);
+        }
+        while !streams.1.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            streams = self.closed.wait_timeout(streams, deadline - now).unwrap().0;
+        }
+        true
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.connections.streams.lock().unwrap().1.remove(&self.id);
+        self.connections.closed.notify_all();
+    }
+}
+
+// Serves the requests of a single connection on a pool thread.
+struct Handler<E: KvsEngine> {
+    engine: E,
+    auth: Option<Arc<Authenticator>>,
+    policy: Option<Arc<Policy>>,
+    protocol: Protocol,
+}
+
+impl <E: KvsEngine> Handler<E> {
+    fn serve(&self, ts: Stream) -> Result<()> {
+        let session = Session::new(self.auth.clone(), self.policy.clone());
+        match self.protocol {
+            Protocol::Kvs => self.serve_kvs(ts, session),
+            Protocol::Resp => resp::serve(&self.engine, ts, session),
+            Protocol::Http => http::serve(&self.engine, ts, session),
+        }
+    }
+
+    fn serve_kvs(&self, ts: Stream, mut session: Session) -> Result<()> {
+        let mut reader = BufReader::new(&ts);
+        let mut writer = BufWriter::new(&ts);
+        let hello = match receive::<Hello>(&ts, &mut reader) {
+            Ok(Some(hello)) => hello,
+            Ok(None) => return Ok(()),
+            Err(error) => {
+                if protocol::is_invalid_input(&error) {
+                    let _ = protocol::send(&mut writer, &HelloResponse::Err(format!("invalid handshake: {}", error)));
+                }
+                return Err(error);
+            }
+        };
+        let version = match Hello::local().negotiate(&hello) {
+            Ok(agreed) => {
+                debug!("agreed on protocol version {}", agreed.version);
+                let version = agreed.version;
+                protocol::send(&mut writer, &HelloResponse::Ok(agreed))?;
+                version
+            },
+            Err(msg) => {
+                protocol::send(&mut writer, &HelloResponse::Err(msg.clone()))?;
+                return Err(KvsError::Handshake(msg));
+            }
+        };
+        // requests are answered in the order they arrive, and the answers
+        // to a pipelined burst go out together once it has been read
+        loop {
+            if reader.buffer().is_empty() {
+                writer.flush()?;
+            }
+            let request = match receive::<Request>(&ts, &mut reader) {
+                Ok(Some(request)) => request,
+                Ok(None) => break,
+                Err(error) => {
+                    // the connection is closed either way, but a client that
+                    // sent something unreadable is told why
+                    if protocol::is_invalid_input(&error) {
+                        let result = Err(ResponseError{
+                            code: ErrorCode::InvalidRequest,
+                            message: format!("invalid request: {}", error),
+                        });
+                        let _ = protocol::send(&mut writer, &Response{id: 0, result});
+                    }
+                    return Err(error);
+                }
+            };
+            let req = request.req;
+            let result = req.check_version(version).and_then(|()| match req {
+                Req::Auth { token } => session.login(&token).map(|()| Reply::Done),
+                req => authorize(&session, &req).and_then(|()| execute(&self.engine, &session, req)),
+            });
+            let result = result.map_err(|error| response_error(error, version));
+            protocol::queue(&mut writer, &Response{id: request.id, result})?;
+        }
+        writer.flush()?;
         Ok(())
     }
 }
 
-fn send_resp<T: Serialize>(ts: &TcpStream, s: T) -> Result<()> {
-    let mut wr =  BufWriter::new(ts);
-    serde_json::to_writer(&mut wr ,&s)?;
-    wr.flush()?;
-    Ok(())
+// Converts a failed request's error for a client on protocol `version`.
+// Shared with the async server.
+pub(crate) fn response_error(error: KvsError, version: u32) -> ResponseError {
+    let error = ResponseError::from(error);
+    ResponseError{code: error.code.for_version(version), message: error.message}
 }
 
+// Fails unless the session may make the request. Shared with the async
+// server.
+pub(crate) fn authorize(session: &Session, req: &Req) -> Result<()> {
+    match req {
+        Req::Get { key } => session.authorize(Operation::Read, key),
+        Req::Set { key, .. } | Req::SetWithTtl { key, .. } => session.authorize(Operation::Write, key),
+        Req::Remove { key } => session.authorize(Operation::Delete, key),
+        // a batch is refused as a whole if any of its writes is
+        Req::Batch(batch) => batch.ops.iter().try_for_each(|op| match op {
+            WireBatchOp::Set { key, .. } => session.authorize(Operation::Write, key),
+            WireBatchOp::Remove { key } => session.authorize(Operation::Delete, key),
+        }),
+        // keys the session may not read are left out of the page
+        Req::Scan { .. } => session.check(),
+        Req::Auth { .. } => Ok(()),
+    }
+}
 
-#[derive(Serialize, Deserialize, Debug)]
-pub enum Req{
-    Set {key: String, value: String},
-    Remove {key: String},
-    Get {key: String},
+// Runs a request against the engine. Shared with the async server.
+pub(crate) fn execute<E: KvsEngine>(engine: &E, session: &Session, req: Req) -> Result<Reply> {
+    match req {
+        Req::Get { key } => engine.get(&key).map(Reply::Value),
+        Req::Set { key, value } => engine.set(key, value).map(|()| Reply::Done),
+        Req::SetWithTtl { key, value, ttl_ms } => {
+            engine.set_with_ttl(key, value, Duration::from_millis(ttl_ms)).map(|()| Reply::Done)
+        },
+        Req::Remove { key } => engine.remove(&key).map(|()| Reply::Done),
+        Req::Batch(batch) => engine.apply_batch(batch.into()).map(|()| Reply::Done),
+        Req::Scan { prefix, start, end, limit } => scan_page(engine, session, &prefix, start, end, limit).map(Reply::Page),
+        // servers that require authentication check tokens before getting
+        // here, and the others accept any
+        Req::Auth { .. } => Ok(Reply::Done),
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub enum GetResponse {
-    Ok(Option<String>),
-    Err(String),
+// Collects one page of a scan, leaving out the keys `session` may not read.
+// Shared with the HTTP API.
+pub(crate) fn scan_page<E: KvsEngine>(
+    engine: &E,
+    session: &Session,
+    prefix: &[u8],
+    start: Option<Vec<u8>>,
+    end: Option<Vec<u8>>,
+    limit: u32,
+) -> Result<Page> {
+    let (mut lower, mut upper) = prefix_range(prefix);
+    if let Some(start) = start {
+        if !matches!(&lower, Bound::Included(lower) if *lower >= start) {
+            lower = Bound::Included(start);
+        }
+    }
+    if let Some(end) = end {
+        if !matches!(&upper, Bound::Excluded(upper) if *upper <= end) {
+            upper = Bound::Excluded(end);
+        }
+    }
+    let limit = limit.clamp(1, MAX_SCAN_LIMIT) as usize;
+    let mut entries = Vec::new();
+    let mut size = 0;
+    for (examined, entry) in engine.scan((lower, upper))?.enumerate() {
+        let (key, value) = entry?;
+        if examined == MAX_SCAN_EXAMINED {
+            return Ok(Page{entries, next: Some(key)});
+        }
+        if session.authorize(Operation::Read, &key).is_err() {
+            continue;
+        }
+        if entries.len() == limit || (size + key.len() + value.len() > MAX_PAGE_BYTES && !entries.is_empty()) {
+            return Ok(Page{entries, next: Some(key)});
+        }
+        size += key.len() + value.len();
+        entries.push(KeyValue{key, value});
+    }
+    Ok(Page{entries, next: None})
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub enum SetResponse {
-    Ok(()),
-    Err(String),
+// Reads the next message from a connection, or returns `None` once the
+// client hangs up between messages.
+fn receive<T: DeserializeOwned>(ts: &Stream, reader: &mut BufReader<&Stream>) -> Result<Option<T>> {
+    if !wait_for_message(ts, reader)? {
+        return Ok(None);
+    }
+    protocol::receive(reader)
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub enum RemoveResponse {
-    Ok(()),
-    Err(String),
-}
\ No newline at end of file
+// Waits until the next message starts to arrive, returning false if the
+// connection is closed instead. A connection may sit idle between messages
+// for as long as it likes, but once a message has started the rest of it must
+// arrive promptly.
+pub(crate) fn wait_for_message(ts: &Stream, reader: &mut BufReader<&Stream>) -> Result<bool> {
+    ts.set_read_timeout(None)?;
+    if reader.fill_buf()?.is_empty() {
+        return Ok(false);
+    }
+    ts.set_read_timeout(Some(FRAME_READ_TIMEOUT))?;
+    Ok(true)
+}
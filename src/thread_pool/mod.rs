@@ -0,0 +1,20 @@
+use crate::Result;
+
+pub trait ThreadPool {
+    fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized;
+
+    // Runs the job on one of the pool's threads. A panicking job must not
+    // take the pool down with it.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
+
+mod naive;
+pub use self::naive::NaiveThreadPool;
+mod shared_queue;
+pub use self::shared_queue::SharedQueueThreadPool;
+mod rayon;
+pub use self::rayon::RayonThreadPool;
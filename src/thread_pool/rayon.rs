@@ -0,0 +1,29 @@
+use super::ThreadPool;
+use crate::{KvsError, Result};
+use log::error;
+
+// Wraps a rayon thread pool.
+pub struct RayonThreadPool(rayon::ThreadPool);
+
+impl ThreadPool for RayonThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        // rayon would pick a number of threads itself
+        if threads == 0 {
+            return Err(KvsError::StringError("thread pool needs at least one thread".to_owned()));
+        }
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            // rayon aborts the process on a panicking job by default
+            .panic_handler(|_| error!("thread pool job panicked"))
+            .build()
+            .map_err(|e| KvsError::StringError(e.to_string()))?;
+        Ok(RayonThreadPool(pool))
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.0.spawn(job)
+    }
+}
@@ -0,0 +1,60 @@
+use super::ThreadPool;
+use crate::{KvsError, Result};
+use crossbeam_channel::{Receiver, Sender};
+use log::error;
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// A fixed number of threads taking jobs from one shared queue. A thread
+// whose job panics is replaced by a fresh one.
+pub struct SharedQueueThreadPool {
+    tx: Sender<Job>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        // with no worker, the queue would be closed and `spawn` would panic
+        if threads == 0 {
+            return Err(KvsError::StringError("thread pool needs at least one thread".to_owned()));
+        }
+        let (tx, rx) = crossbeam_channel::unbounded::<Job>();
+        for _ in 0..threads {
+            let worker = Worker(rx.clone());
+            thread::Builder::new().spawn(move || worker.run())?;
+        }
+        Ok(SharedQueueThreadPool { tx })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.tx
+            .send(Box::new(job))
+            .expect("thread pool has no threads left");
+    }
+}
+
+#[derive(Clone)]
+struct Worker(Receiver<Job>);
+
+impl Worker {
+    // Returns once every sender, i.e. the pool, is gone.
+    fn run(self) {
+        while let Ok(job) = self.0.recv() {
+            job();
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let worker = self.clone();
+            if let Err(e) = thread::Builder::new().spawn(move || worker.run()) {
+                error!("failed to replace panicked thread pool worker: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{Authenticator, KvStore, KvsClient, KvsEngine, KvsError, KvsServer, Policy, Reply, Req, Result, WriteBatch};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const POLICY: &str = "
+# team a owns its keys and may read team b's
+alice  read,write,delete  a/*
+alice  read               b/
+bob    read,write         b/
+admin  admin              *
+";
+
+fn forbidden<T>(res: Result<T>) -> bool {
+    matches!(res, Err(KvsError::Forbidden(_)))
+}
+
+// Each user should only be allowed the operations their rules grant on the
+// keys under the rules' prefixes, whichever protocol they use.
+#[test]
+fn access_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut auth = Authenticator::default();
+    auth.add_entries("alice:secret1,bob:secret2,admin:secret3,carol:secret4")?;
+    let mut policy = Policy::default();
+    policy.add_rules(POLICY)?;
+    let addr = "127.0.0.1:4600";
+    let resp_addr = "127.0.0.1:4601";
+    let http_addr = "127.0.0.1:4602";
+    let server = KvsServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?)
+        .with_auth(Arc::new(auth))
+        .with_policy(Arc::new(policy))
+        .with_resp(resp_addr.parse().unwrap())
+        .with_http(http_addr.parse().unwrap());
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.run(addr.parse().unwrap()));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut alice = KvsClient::connect(addr)?;
+    alice.authenticate("secret1")?;
+    alice.set(b"a/1".to_vec(), b"value1".to_vec())?;
+    assert!(forbidden(alice.set(b"b/1".to_vec(), b"value1".to_vec())));
+    assert!(forbidden(alice.get(b"c/1")));
+    // batches are checked write by write and refused as a whole
+    let mut batch = WriteBatch::new();
+    batch.set(b"a/3".to_vec(), b"value2".to_vec()).remove(b"a/1".to_vec());
+    alice.apply_batch(batch.clone())?;
+    batch.set(b"b/3".to_vec(), b"value3".to_vec());
+    assert!(forbidden(alice.apply_batch(batch)));
+    assert_eq!(alice.get(b"a/1")?, None);
+    alice.set(b"a/1".to_vec(), b"value1".to_vec())?;
+    drop(alice);
+
+    let mut bob = KvsClient::connect(addr)?;
+    bob.authenticate("secret2")?;
+    bob.set(b"b/1".to_vec(), b"value2".to_vec())?;
+    assert!(forbidden(bob.get(b"a/1")));
+    assert!(forbidden(bob.remove(b"b/1")));
+    // scans leave out the keys a user may not read
+    let keys = bob.scan(..).map(|entry| entry.map(|(key, _)| key)).collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, vec![b"b/1".to_vec()]);
+    drop(bob);
+
+    let mut carol = KvsClient::connect(addr)?;
+    carol.authenticate("secret4")?;
+    assert!(forbidden(carol.get(b"a/1")));
+    assert_eq!(carol.scan(..).count(), 0);
+    drop(carol);
+
+    let mut admin = KvsClient::connect(addr)?;
+    admin.authenticate("secret3")?;
+    assert_eq!(admin.get(b"a/1")?, Some(b"value1".to_vec()));
+    admin.remove(b"b/1")?;
+    drop(admin);
+
+    // a command touching any forbidden key is refused as a whole
+    let mut reader = BufReader::new(TcpStream::connect(resp_addr)?);
+    let mut resp = |command: &str| {
+        reader.get_mut().write_all(format!("{}\r\n", command).as_bytes()).unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        line.trim_end().to_string()
+    };
+    assert_eq!(resp("AUTH alice secret1"), "+OK");
+    assert!(resp("MSET a/2 x b/2 y").starts_with("-NOPERM "));
+    assert_eq!(resp("EXISTS a/2"), ":0");
+    assert!(resp("DEL b/1").starts_with("-NOPERM "));
+    drop(reader);
+
+    let mut stream = TcpStream::connect(http_addr)?;
+    stream.write_all(b"DELETE /keys/a/1 HTTP/1.1\r\nAuthorization: Bearer secret2\r\nConnection: close\r\n\r\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    assert!(response.starts_with("HTTP/1.1 403 Forbidden"));
+    assert!(response.contains(r#""code":"Forbidden""#));
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
+
+// A page should stop after a bounded number of keys even if the user may read
+// none of them, and the scan should pick up where it stopped.
+#[test]
+fn scan_skips_hidden_keys_in_bounded_pages() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..20_000 {
+        store.set(format!("a/{:05}", i).into_bytes(), b"value".to_vec())?;
+    }
+    store.set(b"b/1".to_vec(), b"value".to_vec())?;
+    let mut auth = Authenticator::default();
+    auth.add_entries("bob:secret2")?;
+    let mut policy = Policy::default();
+    policy.add_rules(POLICY)?;
+    let addr = "127.0.0.1:4603";
+    let server = KvsServer::new(store, SharedQueueThreadPool::new(2)?)
+        .with_auth(Arc::new(auth))
+        .with_policy(Arc::new(policy));
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.run(addr.parse().unwrap()));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut bob = KvsClient::connect(addr)?;
+    bob.authenticate("secret2")?;
+    let scan = Req::Scan{prefix: Vec::new(), start: None, end: None, limit: 10};
+    match bob.pipeline(vec![scan])?.remove(0)? {
+        Reply::Page(page) => {
+            assert!(page.entries.is_empty());
+            assert!(page.next.is_some_and(|next| next.starts_with(b"a/")));
+        },
+        reply => panic!("unexpected reply: {:?}", reply),
+    }
+    let keys = bob.scan(..).map(|entry| entry.map(|(key, _)| key)).collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, vec![b"b/1".to_vec()]);
+    drop(bob);
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
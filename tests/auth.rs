@@ -0,0 +1,120 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{AsyncKvsClient, AsyncKvsServer, Authenticator, KvStore, KvsClient, KvsError, KvsServer, Policy, Result};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Sends one HTTP request on its own connection and returns the status line.
+fn http_status(addr: &str, method: &str, path: &str, token: Option<&str>) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let auth = token.map(|token| format!("Authorization: Bearer {}\r\n", token)).unwrap_or_default();
+    let head = format!("{} {} HTTP/1.1\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n", method, path, auth);
+    stream.write_all(head.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response.lines().next().unwrap().to_string()
+}
+
+// Sends an inline RESP command and returns the first line of the reply.
+fn resp(reader: &mut BufReader<TcpStream>, command: &str) -> String {
+    reader.get_mut().write_all(format!("{}\r\n", command).as_bytes()).unwrap();
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    line.trim_end().to_string()
+}
+
+// Every listener should refuse requests until a configured token is given.
+#[test]
+fn token_authentication() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut auth = Authenticator::default();
+    auth.add_entries("# users\nalice:secret1, bob:secret2\n")?;
+    let addr = "127.0.0.1:4500";
+    let resp_addr = "127.0.0.1:4501";
+    let http_addr = "127.0.0.1:4502";
+    let server = KvsServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?)
+        .with_auth(Arc::new(auth))
+        .with_resp(resp_addr.parse().unwrap())
+        .with_http(http_addr.parse().unwrap());
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.run(addr.parse().unwrap()));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(matches!(client.get(b"key1"), Err(KvsError::Unauthorized(_))));
+    assert!(matches!(client.authenticate("wrong"), Err(KvsError::Unauthorized(_))));
+    assert!(matches!(client.set(b"key1".to_vec(), b"value1".to_vec()), Err(KvsError::Unauthorized(_))));
+    client.authenticate("secret1")?;
+    client.set(b"key1".to_vec(), b"value1".to_vec())?;
+    assert_eq!(client.get(b"key1")?, Some(b"value1".to_vec()));
+    // a failed login drops the one that came before it
+    assert!(matches!(client.authenticate("wrong"), Err(KvsError::Unauthorized(_))));
+    assert!(matches!(client.get(b"key1"), Err(KvsError::Unauthorized(_))));
+    drop(client);
+    // authentication lasts only as long as the connection
+    let mut client = KvsClient::connect(addr)?;
+    assert!(matches!(client.get(b"key1"), Err(KvsError::Unauthorized(_))));
+    drop(client);
+
+    let mut reader = BufReader::new(TcpStream::connect(resp_addr)?);
+    assert!(resp(&mut reader, "GET key1").starts_with("-NOAUTH "));
+    assert!(resp(&mut reader, "AUTH wrong").starts_with("-WRONGPASS "));
+    assert!(resp(&mut reader, "AUTH alice secret2").starts_with("-WRONGPASS "));
+    assert_eq!(resp(&mut reader, "AUTH bob secret2"), "+OK");
+    assert_eq!(resp(&mut reader, "GET key1"), "$6");
+    let mut value = String::new();
+    reader.read_line(&mut value)?;
+    assert!(resp(&mut reader, "AUTH alice secret2").starts_with("-WRONGPASS "));
+    assert!(resp(&mut reader, "GET key1").starts_with("-NOAUTH "));
+    drop(reader);
+
+    assert_eq!(http_status(http_addr, "GET", "/health", None), "HTTP/1.1 200 OK");
+    assert_eq!(http_status(http_addr, "GET", "/keys/key1", None), "HTTP/1.1 401 Unauthorized");
+    assert_eq!(http_status(http_addr, "GET", "/keys/key1", Some("wrong")), "HTTP/1.1 401 Unauthorized");
+    assert_eq!(http_status(http_addr, "GET", "/keys/key1", Some("secret2")), "HTTP/1.1 200 OK");
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
+
+// The async server should require tokens and apply a policy the same way.
+#[tokio::test(flavor = "multi_thread")]
+async fn async_token_authentication() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut auth = Authenticator::default();
+    auth.add_entries("alice:secret1, bob:secret2")?;
+    let mut policy = Policy::default();
+    policy.add_rules("alice read,write *\nbob read *")?;
+    let addr = "127.0.0.1:4503";
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(
+        AsyncKvsServer::new(KvStore::open(temp_dir.path())?)
+            .with_auth(Arc::new(auth))
+            .with_policy(Arc::new(policy))
+            .run_until(addr.parse().unwrap(), async {
+                let _ = stopped.await;
+            }),
+    );
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let mut client = AsyncKvsClient::connect(addr).await?;
+    assert!(matches!(client.get(b"key1").await, Err(KvsError::Unauthorized(_))));
+    assert!(matches!(client.authenticate("wrong").await, Err(KvsError::Unauthorized(_))));
+    client.authenticate("secret1").await?;
+    client.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+    client.authenticate("secret2").await?;
+    assert_eq!(client.get(b"key1").await?, Some(b"value1".to_vec()));
+    assert!(matches!(client.remove(b"key1").await, Err(KvsError::Forbidden(_))));
+    assert!(matches!(client.authenticate("wrong").await, Err(KvsError::Unauthorized(_))));
+    assert!(matches!(client.get(b"key1").await, Err(KvsError::Unauthorized(_))));
+    drop(client);
+
+    let mut client = AsyncKvsClient::connect(addr).await?;
+    assert!(matches!(client.get(b"key1").await, Err(KvsError::Unauthorized(_))));
+
+    stop.send(()).unwrap();
+    server.await.unwrap()
+}
@@ -0,0 +1,259 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{AsyncKvsClient, AsyncKvsServer, KeyValue, KvStore, KvsClient, KvsError, KvsServer, Reply, Req, Result, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::ops::Bound;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Many requests should share one connection, with server errors coming back
+// as typed errors.
+#[test]
+fn client_reuses_connection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4100";
+    let server = KvsServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?);
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.run(addr.parse().unwrap()));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.protocol_version(), PROTOCOL_VERSION);
+    for i in 0..100 {
+        client.set(format!("key{}", i).into_bytes(), format!("value{}", i).into_bytes())?;
+    }
+    for i in 0..100 {
+        assert_eq!(
+            client.get(format!("key{}", i).as_bytes())?,
+            Some(format!("value{}", i).into_bytes())
+        );
+    }
+    client.remove(b"key0")?;
+    assert_eq!(client.get(b"key0")?, None);
+    assert!(matches!(client.remove(b"key0"), Err(KvsError::KeyNotFound)));
+    drop(client);
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
+
+// A pipeline larger than the client's window should come back complete and
+// in order, with failed requests not affecting the others.
+#[test]
+fn client_pipeline() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4103";
+    let server = KvsServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?);
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.run(addr.parse().unwrap()));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    let mut reqs = Vec::new();
+    for i in 0..1000 {
+        let key = format!("key{}", i).into_bytes();
+        reqs.push(Req::Set{key: key.clone(), value: format!("value{}", i).into_bytes()});
+        reqs.push(Req::Get{key});
+    }
+    reqs.push(Req::Remove{key: b"missing".to_vec()});
+    let results = client.pipeline(reqs)?;
+    assert_eq!(results.len(), 2001);
+    for i in 0..1000 {
+        assert_eq!(results[2 * i].as_ref().unwrap(), &Reply::Done);
+        assert_eq!(
+            results[2 * i + 1].as_ref().unwrap(),
+            &Reply::Value(Some(format!("value{}", i).into_bytes()))
+        );
+    }
+    assert!(matches!(results[2000], Err(KvsError::KeyNotFound)));
+
+    // the connection is still usable afterwards
+    assert_eq!(client.get(b"key999")?, Some(b"value999".to_vec()));
+    drop(client);
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
+
+// Scans should stream every page of their range, and a single `Scan`
+// request should stop at its limit and say where to continue.
+#[test]
+fn client_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4104";
+    let server = KvsServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?);
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.run(addr.parse().unwrap()));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    let reqs = (0..600)
+        .map(|i| Req::Set{key: format!("key{:03}", i).into_bytes(), value: format!("value{}", i).into_bytes()})
+        .collect();
+    assert!(client.pipeline(reqs)?.into_iter().all(|result| result.is_ok()));
+    client.set(b"other".to_vec(), b"value".to_vec())?;
+
+    let all = client.scan(..).collect::<Result<Vec<_>>>()?;
+    assert_eq!(all.len(), 601);
+    assert_eq!(all[599], (b"key599".to_vec(), b"value599".to_vec()));
+    assert_eq!(client.scan_prefix(b"key").count(), 600);
+    let keys = client.scan(b"key100".to_vec()..=b"key102".to_vec())
+        .map(|entry| entry.map(|(key, _)| key))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, vec![b"key100".to_vec(), b"key101".to_vec(), b"key102".to_vec()]);
+    assert_eq!(client.scan_prefix_range(b"key1", (Bound::Excluded(b"key198".to_vec()), Bound::Unbounded)).count(), 1);
+
+    let req = Req::Scan{prefix: b"key".to_vec(), start: Some(b"key598".to_vec()), end: None, limit: 1};
+    match client.pipeline(vec![req])?.remove(0)? {
+        Reply::Page(page) => {
+            assert_eq!(page.entries, vec![KeyValue{key: b"key598".to_vec(), value: b"value598".to_vec()}]);
+            assert_eq!(page.next, Some(b"key599".to_vec()));
+        },
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+    drop(client);
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
+
+// The async server and client should interoperate with each other and with
+// the blocking client.
+#[tokio::test(flavor = "multi_thread")]
+async fn async_client_and_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4101";
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(
+        AsyncKvsServer::new(KvStore::open(temp_dir.path())?)
+            .run_until(addr.parse().unwrap(), async {
+                let _ = stopped.await;
+            }),
+    );
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let mut tasks = Vec::new();
+    for task_id in 0..10 {
+        tasks.push(tokio::spawn(async move {
+            let mut client = AsyncKvsClient::connect(addr).await?;
+            for i in 0..20 {
+                let key = format!("key{}", task_id * 20 + i).into_bytes();
+                client.set(key.clone(), vec![task_id as u8, i as u8]).await?;
+                assert_eq!(client.get(&key).await?, Some(vec![task_id as u8, i as u8]));
+            }
+            Ok::<(), KvsError>(())
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap()?;
+    }
+
+    let mut client = AsyncKvsClient::connect(addr).await?;
+    client.remove(b"key0").await?;
+    assert!(matches!(client.remove(b"key0").await, Err(KvsError::KeyNotFound)));
+    let value = tokio::task::spawn_blocking(move || KvsClient::connect(addr)?.get(b"key199"))
+        .await
+        .unwrap()?;
+    assert_eq!(value, Some(vec![9, 19]));
+
+    stop.send(()).unwrap();
+    server.await.unwrap()
+}
+
+// Sends one length-prefixed JSON message and reads the server's reply.
+fn exchange(stream: &mut TcpStream, msg: &str) -> String {
+    stream.write_all(&(msg.len() as u32).to_be_bytes()).unwrap();
+    stream.write_all(msg.as_bytes()).unwrap();
+    let mut len = [0; 4];
+    stream.read_exact(&mut len).unwrap();
+    let mut reply = vec![0; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut reply).unwrap();
+    String::from_utf8(reply).unwrap()
+}
+
+// Clients outside the server's version range, or that skip the handshake,
+// should be refused with an error explaining why.
+#[test]
+fn handshake_refuses_incompatible_clients() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4102";
+    let server = KvsServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?);
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.run(addr.parse().unwrap()));
+    thread::sleep(Duration::from_millis(500));
+
+    let future = PROTOCOL_VERSION + 1;
+    let mut stream = TcpStream::connect(addr)?;
+    let reply = exchange(
+        &mut stream,
+        &format!(r#"{{"version":{},"min_version":{},"capabilities":[]}}"#, future, future),
+    );
+    assert!(reply.contains("incompatible protocol versions"), "{}", reply);
+
+    // a newer client that can still speak this version is downgraded
+    let mut stream = TcpStream::connect(addr)?;
+    let reply = exchange(
+        &mut stream,
+        &format!(r#"{{"version":{},"min_version":1,"capabilities":["unknown"]}}"#, future),
+    );
+    assert_eq!(
+        reply,
+        format!(r#"{{"Ok":{{"version":{},"min_version":{},"capabilities":[]}}}}"#, PROTOCOL_VERSION, PROTOCOL_VERSION)
+    );
+    let reply = exchange(&mut stream, r#"{"id":7,"req":{"Remove":{"key":"a2V5"}}}"#);
+    assert_eq!(reply, r#"{"id":7,"result":{"Err":{"code":"KeyNotFound","message":"Key not found"}}}"#);
+    let reply = exchange(&mut stream, r#"{"id":8,"req":{"Get":{"key":"not base64!"}}}"#);
+    assert!(reply.starts_with(r#"{"id":0,"result":{"Err":{"code":"InvalidRequest""#), "{}", reply);
+
+    let mut stream = TcpStream::connect(addr)?;
+    let reply = exchange(&mut stream, r#"{"Get":{"key":"a2V5"}}"#);
+    assert!(reply.contains("invalid handshake"), "{}", reply);
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
+
+// Clients on an older version should still be served, but refused requests
+// their version does not have.
+#[test]
+fn handshake_accepts_older_clients() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4105";
+    let server = KvsServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?);
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.run(addr.parse().unwrap()));
+    thread::sleep(Duration::from_millis(500));
+
+    let previous = PROTOCOL_VERSION - 1;
+    let mut stream = TcpStream::connect(addr)?;
+    let reply = exchange(
+        &mut stream,
+        &format!(r#"{{"version":{},"min_version":{},"capabilities":[]}}"#, previous, previous),
+    );
+    assert_eq!(
+        reply,
+        format!(r#"{{"Ok":{{"version":{},"min_version":{},"capabilities":[]}}}}"#, previous, previous)
+    );
+    let reply = exchange(&mut stream, r#"{"id":1,"req":{"Set":{"key":"a2V5","value":"dmFsdWU="}}}"#);
+    assert_eq!(reply, r#"{"id":1,"result":{"Ok":"Done"}}"#);
+    let reply = exchange(&mut stream, r#"{"id":2,"req":{"Batch":{"ops":[]}}}"#);
+    assert!(reply.starts_with(r#"{"id":2,"result":{"Err":{"code":"InvalidRequest""#), "{}", reply);
+    let reply = exchange(&mut stream, r#"{"id":3,"req":{"Get":{"key":"a2V5"}}}"#);
+    assert_eq!(reply, r#"{"id":3,"result":{"Ok":{"Value":"dmFsdWU="}}}"#);
+
+    // down to the oldest version still supported
+    let mut stream = TcpStream::connect(addr)?;
+    let reply = exchange(
+        &mut stream,
+        &format!(r#"{{"version":{},"min_version":{},"capabilities":[]}}"#, MIN_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION),
+    );
+    assert!(reply.starts_with(&format!(r#"{{"Ok":{{"version":{},"#, MIN_PROTOCOL_VERSION)), "{}", reply);
+    let reply = exchange(&mut stream, r#"{"id":1,"req":{"Get":{"key":"a2V5"}}}"#);
+    assert_eq!(reply, r#"{"id":1,"result":{"Ok":{"Value":"dmFsdWU="}}}"#);
+    let reply = exchange(&mut stream, r#"{"id":2,"req":{"Scan":{"prefix":"","start":null,"end":null,"limit":10}}}"#);
+    assert!(reply.starts_with(r#"{"id":2,"result":{"Err":{"code":"InvalidRequest""#), "{}", reply);
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
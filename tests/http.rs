@@ -0,0 +1,89 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsClient, KvsServer, Result};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Sends one request on its own connection and returns the whole response.
+fn request(addr: &str, method: &str, path: &str, body: &[u8]) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let head = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        method,
+        path,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).unwrap();
+    stream.write_all(body).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+fn status(response: &str) -> &str {
+    &response[9..12]
+}
+
+fn body(response: &str) -> &str {
+    response.split_once("\r\n\r\n").unwrap().1
+}
+
+// The HTTP API should read and write the same engine as the kvs protocol,
+// with errors mapped to status codes.
+#[test]
+fn http_gateway() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4300";
+    let http_addr = "127.0.0.1:4301";
+    let server = KvsServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?)
+        .with_http(http_addr.parse().unwrap());
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.run(addr.parse().unwrap()));
+    thread::sleep(Duration::from_millis(500));
+
+    let response = request(http_addr, "GET", "/health", b"");
+    assert_eq!(status(&response), "200");
+    assert_eq!(body(&response), r#"{"status":"ok"}"#);
+
+    assert_eq!(status(&request(http_addr, "PUT", "/keys/key%201", b"value1")), "204");
+    let response = request(http_addr, "GET", "/keys/key%201", b"");
+    assert_eq!(status(&response), "200");
+    assert_eq!(body(&response), "value1");
+    assert_eq!(KvsClient::connect(addr)?.get(b"key 1")?, Some(b"value1".to_vec()));
+
+    assert_eq!(status(&request(http_addr, "DELETE", "/keys/key%201", b"")), "204");
+    let response = request(http_addr, "GET", "/keys/key%201", b"");
+    assert_eq!(status(&response), "404");
+    assert!(body(&response).contains(r#""code":"KeyNotFound""#));
+    assert_eq!(status(&request(http_addr, "DELETE", "/keys/key%201", b"")), "404");
+
+    assert_eq!(status(&request(http_addr, "POST", "/keys/key1", b"")), "405");
+    assert_eq!(status(&request(http_addr, "GET", "/nowhere", b"")), "404");
+    assert_eq!(status(&request(http_addr, "GET", "/keys/bad%zz", b"")), "400");
+
+    // several requests on one kept-alive connection
+    let mut stream = TcpStream::connect(http_addr)?;
+    stream.write_all(b"PUT /keys/a HTTP/1.1\r\nContent-Length: 1\r\n\r\nxGET /keys/a HTTP/1.1\r\n\r\n")?;
+    stream.write_all(b"GET /keys/a HTTP/1.1\r\nConnection: close\r\n\r\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    assert_eq!(response.matches("HTTP/1.1 ").count(), 3);
+    assert!(response.ends_with("\r\n\r\nx"));
+
+    // pages of keys and values in base64
+    assert_eq!(status(&request(http_addr, "PUT", "/keys/b", b"y")), "204");
+    assert_eq!(status(&request(http_addr, "PUT", "/keys/c", b"z")), "204");
+    let response = request(http_addr, "GET", "/keys?limit=2", b"");
+    assert_eq!(status(&response), "200");
+    assert_eq!(body(&response), r#"{"entries":[{"key":"YQ==","value":"eA=="},{"key":"Yg==","value":"eQ=="}],"next":"Yw=="}"#);
+    let response = request(http_addr, "GET", "/keys?start=c", b"");
+    assert_eq!(body(&response), r#"{"entries":[{"key":"Yw==","value":"eg=="}],"next":null}"#);
+    let response = request(http_addr, "GET", "/keys?prefix=b&end=c", b"");
+    assert_eq!(body(&response), r#"{"entries":[{"key":"Yg==","value":"eQ=="}],"next":null}"#);
+    assert_eq!(status(&request(http_addr, "GET", "/keys?limit=many", b"")), "400");
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
@@ -1,24 +1,62 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{KvStore, KvsEngine, KvsError, Result, Sled, WriteBatch};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
+fn log_files(dir: &Path) -> Vec<PathBuf> {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|res| res.ok())
+        .map(|entry| entry.into_path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .collect()
+}
+
+// Runs a test written against `KvsEngine`, given a function that opens the
+// store, once for each engine.
+macro_rules! engine_tests {
+    ($test:ident) => {
+        mod $test {
+            use super::*;
+
+            #[test]
+            fn kvs() -> Result<()> {
+                let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+                super::$test(|| KvStore::open(temp_dir.path()))
+            }
+
+            #[test]
+            fn sled() -> Result<()> {
+                let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+                super::$test(|| Sled::open(temp_dir.path()))
+            }
+        }
+    };
+}
+
 // Should get previously stored value
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    store.set(b"key2".to_vec(), b"value2".to_vec())?;
 
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get(b"key1")?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2")?, Some(b"value2".to_vec()));
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1")?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2")?, Some(b"value2".to_vec()));
 
     Ok(())
 }
@@ -27,19 +65,19 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    store.set("key1".to_owned(), "value2".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    assert_eq!(store.get(b"key1")?, Some(b"value1".to_vec()));
+    store.set(b"key1".to_vec(), b"value2".to_vec())?;
+    assert_eq!(store.get(b"key1")?, Some(b"value2".to_vec()));
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
-    store.set("key1".to_owned(), "value3".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1")?, Some(b"value2".to_vec()));
+    store.set(b"key1".to_vec(), b"value3".to_vec())?;
+    assert_eq!(store.get(b"key1")?, Some(b"value3".to_vec()));
 
     Ok(())
 }
@@ -48,15 +86,15 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    assert_eq!(store.get(b"key2")?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key2")?, None);
 
     Ok(())
 }
@@ -64,18 +102,18 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert!(store.remove("key1".to_owned()).is_err());
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.remove(b"key1").is_err());
     Ok(())
 }
 
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert!(store.remove("key1".to_owned()).is_ok());
-    assert_eq!(store.get("key1".to_owned())?, None);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    assert!(store.remove(b"key1").is_ok());
+    assert_eq!(store.get(b"key1")?, None);
     Ok(())
 }
 
@@ -84,7 +122,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -102,7 +140,7 @@ fn compaction() -> Result<()> {
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             let value = format!("{}", iter);
-            store.set(key, value)?;
+            store.set(key.into_bytes(), value.into_bytes())?;
         }
 
         let new_size = dir_size();
@@ -114,13 +152,622 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+            assert_eq!(store.get(key.as_bytes())?, Some(format!("{}", iter).into_bytes()));
         }
         return Ok(());
     }
 
     panic!("No compaction detected");
-}
\ No newline at end of file
+}
+// Overwrites should only be appended until the stale bytes cross the
+// configured threshold.
+#[test]
+fn compaction_threshold() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_threshold(temp_dir.path(), 4096)?;
+
+    let log_size = || {
+        log_files(temp_dir.path())
+            .iter()
+            .map(|path| fs::metadata(path).expect("fail to get log size").len())
+            .sum::<u64>()
+    };
+
+    store.set(b"key".to_vec(), b"value".to_vec())?;
+    let entry_size = log_size();
+    let mut sizes = Vec::new();
+    for iter in 0..1000 {
+        store.set(b"key".to_vec(), format!("{:05}", iter).into_bytes())?;
+        sizes.push(log_size());
+    }
+
+    // no write may rewrite the log while it is under the threshold
+    let first_shrink = sizes.windows(2).position(|w| w[1] < w[0]).expect("No compaction detected");
+    assert!(sizes[first_shrink] > 4096);
+    assert!(sizes.iter().all(|size| *size <= 4096 + 2 * entry_size));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key")?, Some(b"00999".to_vec()));
+    Ok(())
+}
+
+// Keys should be replayed from every segment in order, so values written
+// after a compaction shadow the compacted ones.
+#[test]
+fn replay_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_threshold(temp_dir.path(), 4096)?;
+    store.set(b"kept".to_vec(), b"old".to_vec())?;
+    store.set(b"removed".to_vec(), b"old".to_vec())?;
+    for iter in 0..1000 {
+        store.set(b"key".to_vec(), format!("{:05}", iter).into_bytes())?;
+    }
+    store.set(b"kept".to_vec(), b"new".to_vec())?;
+    store.remove(b"removed")?;
+    drop(store);
+    assert!(log_files(temp_dir.path()).len() >= 2);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key")?, Some(b"00999".to_vec()));
+    assert_eq!(store.get(b"kept")?, Some(b"new".to_vec()));
+    assert_eq!(store.get(b"removed")?, None);
+    Ok(())
+}
+
+// A log left by versions that wrote a single `tmp.log` should become the
+// first segment.
+#[test]
+fn migrate_legacy_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = format!(
+        "{}\n{}\n",
+        r#"{"Set":{"key":"key1","value":"value1"}}"#,
+        r#"{"Set":{"key":"key2","value":"value2"}}"#,
+    );
+    fs::write(temp_dir.path().join("tmp.log"), log)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1")?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2")?, Some(b"value2".to_vec()));
+    store.set(b"key3".to_vec(), b"value3".to_vec())?;
+    drop(store);
+    assert!(!temp_dir.path().join("tmp.log").exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1")?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key3")?, Some(b"value3".to_vec()));
+    Ok(())
+}
+
+// A half-written compaction output should be discarded on open.
+#[test]
+fn remove_leftover_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    drop(store);
+    let leftover = temp_dir.path().join("2.log.tmp");
+    fs::write(&leftover, b"garbage")?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!leftover.exists());
+    assert_eq!(store.get(b"key1")?, Some(b"value1".to_vec()));
+    Ok(())
+}
+
+// A crash after the compacted segment is renamed into place but before the
+// old segments are deleted should replay to the same state.
+#[test]
+fn recover_interrupted_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    store.set(b"key1".to_vec(), b"value2".to_vec())?;
+    store.set(b"key2".to_vec(), b"value3".to_vec())?;
+    store.set(b"key3".to_vec(), b"value4".to_vec())?;
+    store.remove(b"key3")?;
+    drop(store);
+
+    // what compacting segment 1 writes to segment 2
+    let compacted_dir = TempDir::new().expect("unable to create temporary working directory");
+    let compacted = KvStore::open(compacted_dir.path())?;
+    compacted.set(b"key1".to_vec(), b"value2".to_vec())?;
+    compacted.set(b"key2".to_vec(), b"value3".to_vec())?;
+    drop(compacted);
+    fs::copy(compacted_dir.path().join("1.log"), temp_dir.path().join("2.log"))?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1")?, Some(b"value2".to_vec()));
+    assert_eq!(store.get(b"key2")?, Some(b"value3".to_vec()));
+    assert_eq!(store.get(b"key3")?, None);
+    store.set(b"key4".to_vec(), b"value5".to_vec())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1")?, Some(b"value2".to_vec()));
+    assert_eq!(store.get(b"key3")?, None);
+    assert_eq!(store.get(b"key4")?, Some(b"value5".to_vec()));
+    Ok(())
+}
+
+// A half-written record at the end of the log should be dropped on open.
+#[test]
+fn recover_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    store.set(b"key2".to_vec(), b"value2".to_vec())?;
+    drop(store);
+
+    let logs = log_files(temp_dir.path());
+    assert_eq!(logs.len(), 1);
+    let mut f = OpenOptions::new().append(true).open(&logs[0])?;
+    f.write_all(b"0000002a 1badb002 {\"Set\":{\"key\":\"ke")?;
+    drop(f);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1")?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2")?, Some(b"value2".to_vec()));
+    store.set(b"key3".to_vec(), b"value3".to_vec())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key3")?, Some(b"value3".to_vec()));
+    Ok(())
+}
+
+// So should a last record that is whole but fails its checksum, as when
+// only part of it reached the disk.
+#[test]
+fn recover_damaged_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    store.set(b"key2".to_vec(), b"value2".to_vec())?;
+    drop(store);
+
+    let logs = log_files(temp_dir.path());
+    let mut damaged = fs::read(&logs[0])?;
+    let len = damaged.len();
+    damaged[len - 1] = b'X';
+    fs::write(&logs[0], &damaged)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1")?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2")?, None);
+    store.set(b"key3".to_vec(), b"value3".to_vec())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1")?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key3")?, Some(b"value3".to_vec()));
+    Ok(())
+}
+
+// A damaged record followed by intact ones is corruption, not a torn write.
+#[test]
+fn detect_corruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    store.set(b"key2".to_vec(), b"value2".to_vec())?;
+    drop(store);
+
+    let logs = log_files(temp_dir.path());
+    let content = fs::read(&logs[0])?;
+    let at = content
+        .windows(6)
+        .position(|w| w == b"value1")
+        .expect("value not found in log");
+    let mut damaged = content.clone();
+    damaged[at + 5] = b'X';
+    fs::write(&logs[0], damaged)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { offset, .. }) => assert_eq!(offset, 0),
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("corruption not detected"),
+    }
+    Ok(())
+}
+
+// A length damaged so that it points past the end of the segment is
+// corruption too, and must not cut off the records after it.
+#[test]
+fn detect_damaged_length() -> Result<()> {
+    for batched in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        if batched {
+            let mut batch = WriteBatch::new();
+            batch.set(b"key1".to_vec(), b"value1".to_vec());
+            store.apply_batch(batch)?;
+        } else {
+            store.set(b"key1".to_vec(), b"value1".to_vec())?;
+        }
+        store.set(b"key2".to_vec(), b"value2".to_vec())?;
+        drop(store);
+
+        let logs = log_files(temp_dir.path());
+        let mut damaged = fs::read(&logs[0])?;
+        let len = damaged.len();
+        // the length of the first record's key, or of its batched records
+        damaged[4] = 0x10;
+        fs::write(&logs[0], &damaged)?;
+
+        match KvStore::open(temp_dir.path()) {
+            Err(KvsError::Corruption { offset, .. }) => assert_eq!(offset, 0),
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("corruption not detected"),
+        }
+        assert_eq!(fs::metadata(&logs[0])?.len() as usize, len);
+    }
+    Ok(())
+}
+
+// Logs written as JSON lines by older versions should still open and be
+// rewritten in the current format.
+#[test]
+fn upgrade_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let payload = r#"{"Set":{"key":"key2","value":"value2"}}"#;
+    let log = format!(
+        "{}\n{}\n{:08x} {:08x} {}\n",
+        r#"{"Set":{"key":"key1","value":"value1"}}"#,
+        r#"{"Remove":{"key":"key1"}}"#,
+        payload.len(),
+        crc32fast::hash(payload.as_bytes()),
+        payload,
+    );
+    fs::write(temp_dir.path().join("meta.txt"), "kvs")?;
+    fs::write(temp_dir.path().join("1.log"), log)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1")?, None);
+    assert_eq!(store.get(b"key2")?, Some(b"value2".to_vec()));
+    drop(store);
+
+    let meta = fs::read_to_string(temp_dir.path().join("meta.txt"))?;
+    assert_ne!(meta, "kvs");
+    for path in log_files(temp_dir.path()) {
+        assert!(!fs::read(&path)?.contains(&b'{'));
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key2")?, Some(b"value2".to_vec()));
+
+    // logs from a newer version are refused
+    drop(store);
+    fs::write(temp_dir.path().join("meta.txt"), "kvs 999")?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::UnsupportedFormat(999))
+    ));
+    Ok(())
+}
+
+// Records from before headers had their own checksum should still open and be
+// rewritten with one.
+#[test]
+fn upgrade_unchecked_headers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut record = vec![1];
+    record.extend_from_slice(&4u32.to_le_bytes());
+    record.extend_from_slice(&6u32.to_le_bytes());
+    record.extend_from_slice(&[0; 4]);
+    record.extend_from_slice(b"key1value1");
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&record[..9]);
+    hasher.update(&record[13..]);
+    let crc = hasher.finalize();
+    record[9..13].copy_from_slice(&crc.to_le_bytes());
+    fs::write(temp_dir.path().join("meta.txt"), "kvs 4")?;
+    fs::write(temp_dir.path().join("1.log"), &record)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1")?, Some(b"value1".to_vec()));
+    drop(store);
+
+    for path in log_files(temp_dir.path()) {
+        let content = fs::read(&path)?;
+        assert!(content.is_empty() || content[0] & 0x80 != 0);
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1")?, Some(b"value1".to_vec()));
+    Ok(())
+}
+
+// A version 1 Sled store, with values in the default tree and deadlines in
+// a tree of their own, should be upgraded when it is opened.
+#[test]
+fn upgrade_sled_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::open(temp_dir.path())?;
+    db.insert(b"key1", b"value1".to_vec())?;
+    db.insert(b"key2", b"value2".to_vec())?;
+    db.open_tree("ttl")?.insert(b"key2", 1u64.to_be_bytes().to_vec())?;
+    db.flush()?;
+    drop(db);
+    fs::write(temp_dir.path().join("meta.txt"), "sled")?;
+
+    let store = Sled::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1")?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2")?, None);
+    drop(store);
+    assert_eq!(fs::read_to_string(temp_dir.path().join("meta.txt"))?, "sled 2");
+
+    let store = Sled::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1")?, Some(b"value1".to_vec()));
+    assert_eq!(store.scan(..)?.count(), 1);
+    Ok(())
+}
+
+// Keys and values are arbitrary bytes, not necessarily UTF-8.
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let key = vec![0, 159, 146, 150, b'\n'];
+    let value = vec![255, 0, b'\n', b'{', 254];
+
+    store.set(key.clone(), value.clone())?;
+    store.set(Vec::new(), Vec::new())?;
+    assert_eq!(store.get(&key)?, Some(value.clone()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(&key)?, Some(value));
+    assert_eq!(store.get(b"")?, Some(Vec::new()));
+    store.remove(&key)?;
+    assert_eq!(store.get(&key)?, None);
+    Ok(())
+}
+
+// Handles cloned into several threads should see each other's writes, also
+// while compactions are running underneath them.
+#[test]
+fn concurrent_set_and_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_threshold(temp_dir.path(), 4096)?;
+
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || -> Result<()> {
+            for iter in 0..100 {
+                let key = format!("key{}", thread_id * 100 + iter).into_bytes();
+                store.set(key.clone(), format!("value{}", iter).into_bytes())?;
+                assert_eq!(store.get(&key)?, Some(format!("value{}", iter).into_bytes()));
+            }
+            Ok(())
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || -> Result<()> {
+            for key_id in 0..800 {
+                let key = format!("key{}", (key_id + thread_id * 100) % 800);
+                assert!(store.get(key.as_bytes())?.is_some());
+            }
+            Ok(())
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..800 {
+        let key = format!("key{}", key_id);
+        assert_eq!(store.get(key.as_bytes())?, Some(format!("value{}", key_id % 100).into_bytes()));
+    }
+    Ok(())
+}
+
+// Keys with a TTL should read as missing once it passes, including after a
+// restart, while setting a key again without one makes it permanent.
+fn expire_keys<E: KvsEngine>(open: impl Fn() -> Result<E>) -> Result<()> {
+    let store = open()?;
+    store.set_with_ttl(b"short".to_vec(), b"value1".to_vec(), Duration::from_millis(300))?;
+    store.set_with_ttl(b"long".to_vec(), b"value2".to_vec(), Duration::from_secs(3600))?;
+    store.set_with_ttl(b"reset".to_vec(), b"value3".to_vec(), Duration::from_millis(300))?;
+    store.set(b"reset".to_vec(), b"value4".to_vec())?;
+    assert_eq!(store.get(b"short")?, Some(b"value1".to_vec()));
+
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(store.get(b"short")?, None);
+    assert!(matches!(store.remove(b"short"), Err(KvsError::KeyNotFound)));
+    assert_eq!(store.get(b"long")?, Some(b"value2".to_vec()));
+    assert_eq!(store.get(b"reset")?, Some(b"value4".to_vec()));
+
+    store.set_with_ttl(b"restart".to_vec(), b"value5".to_vec(), Duration::from_millis(300))?;
+    drop(store);
+    let store = open()?;
+    assert_eq!(store.get(b"restart")?, Some(b"value5".to_vec()));
+    drop(store);
+    thread::sleep(Duration::from_millis(400));
+    let store = open()?;
+    assert_eq!(store.get(b"restart")?, None);
+    assert_eq!(store.get(b"short")?, None);
+    assert_eq!(store.get(b"long")?, Some(b"value2".to_vec()));
+    assert_eq!(store.get(b"reset")?, Some(b"value4".to_vec()));
+    Ok(())
+}
+
+engine_tests!(expire_keys);
+
+// A reader racing with writes to a key should never see a value that had
+// already expired when it was replaced.
+fn overwrite_expired_key<E: KvsEngine>(open: impl Fn() -> Result<E>) -> Result<()> {
+    let store = open()?;
+    let done = Arc::new(AtomicBool::new(false));
+    let reader = {
+        let store = store.clone();
+        let done = Arc::clone(&done);
+        thread::spawn(move || -> Result<()> {
+            while !done.load(Ordering::SeqCst) {
+                assert_ne!(store.get(b"key")?, Some(b"expired".to_vec()));
+            }
+            Ok(())
+        })
+    };
+    for _ in 0..200 {
+        store.set_with_ttl(b"key".to_vec(), b"expired".to_vec(), Duration::ZERO)?;
+        store.set(b"key".to_vec(), b"live".to_vec())?;
+    }
+    done.store(true, Ordering::SeqCst);
+    reader.join().unwrap()
+}
+
+engine_tests!(overwrite_expired_key);
+
+// Expired keys should be swept in the background and their records
+// reclaimed by compaction, without them coming back.
+#[test]
+fn sweep_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_threshold(temp_dir.path(), 64 * 1024)?;
+    let value = vec![b'x'; 1024];
+    for key_id in 0..100 {
+        let key = format!("key{}", key_id).into_bytes();
+        store.set_with_ttl(key, value.clone(), Duration::from_millis(100))?;
+    }
+    let size = || -> u64 {
+        log_files(temp_dir.path())
+            .iter()
+            .map(|path| fs::metadata(path).expect("fail to get log size").len())
+            .sum()
+    };
+    assert!(size() > 100 * 1024);
+
+    // give the sweeper a chance to run, then write to trigger compaction
+    thread::sleep(Duration::from_millis(2500));
+    store.set(b"key".to_vec(), b"value".to_vec())?;
+    assert!(size() < 1024);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key0")?, None);
+    assert_eq!(store.get(b"key")?, Some(b"value".to_vec()));
+    Ok(())
+}
+
+// Scans should list live keys in order within their range.
+fn scan_keys<E: KvsEngine>(open: impl Fn() -> Result<E>) -> Result<()> {
+    let store = open()?;
+    // enough keys to take several batches
+    for key_id in (0..300).rev() {
+        store.set(format!("key{:03}", key_id).into_bytes(), format!("value{}", key_id).into_bytes())?;
+    }
+    store.set(b"other".to_vec(), b"value".to_vec())?;
+    store.set(vec![0xff, 0xff], b"high".to_vec())?;
+    store.set_with_ttl(b"key150a".to_vec(), b"gone".to_vec(), Duration::from_millis(100))?;
+    store.remove(b"key010")?;
+    thread::sleep(Duration::from_millis(200));
+
+    let keys = |entries: Vec<(Vec<u8>, Vec<u8>)>| -> Vec<Vec<u8>> {
+        entries.into_iter().map(|(key, _)| key).collect()
+    };
+    let all = store.scan(..)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(all.len(), 301);
+    assert_eq!(all[0], (b"key000".to_vec(), b"value0".to_vec()));
+    assert_eq!(all[299], (b"other".to_vec(), b"value".to_vec()));
+    assert_eq!(all[300], (vec![0xff, 0xff], b"high".to_vec()));
+    assert!(all.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+    let range = store.scan(b"key100".to_vec()..b"key103".to_vec())?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys(range), vec![b"key100".to_vec(), b"key101".to_vec(), b"key102".to_vec()]);
+    let range = store.scan(b"key298".to_vec()..)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys(range).len(), 4);
+    let range = store.scan(..=b"key001".to_vec())?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys(range), vec![b"key000".to_vec(), b"key001".to_vec()]);
+    assert_eq!(store.scan(b"key2".to_vec()..b"key1".to_vec())?.count(), 0);
+
+    assert_eq!(store.scan_prefix(b"key01")?.count(), 9);
+    assert_eq!(store.scan_prefix(b"key")?.count(), 299);
+    assert_eq!(store.scan_prefix(&[0xff])?.count(), 1);
+    assert_eq!(store.scan_prefix(b"")?.count(), 301);
+    Ok(())
+}
+
+engine_tests!(scan_keys);
+
+// Batches should apply in order, all at once, and persist.
+fn apply_batches<E: KvsEngine>(open: impl Fn() -> Result<E>) -> Result<()> {
+    let store = open()?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    store.set_with_ttl(b"key2".to_vec(), b"value2".to_vec(), Duration::from_millis(300))?;
+    let mut batch = WriteBatch::new();
+    batch.set(b"key3".to_vec(), b"value3".to_vec())
+        .remove(b"key1".to_vec())
+        .set(b"key2".to_vec(), b"value4".to_vec())
+        .set(b"key4".to_vec(), b"value5".to_vec())
+        .remove(b"key4".to_vec());
+    assert_eq!(batch.len(), 5);
+    store.apply_batch(batch)?;
+    store.apply_batch(WriteBatch::new())?;
+
+    // a failing remove stops the writes before it as well
+    let mut batch = WriteBatch::new();
+    batch.set(b"key5".to_vec(), b"value6".to_vec()).remove(b"key1".to_vec());
+    assert!(matches!(store.apply_batch(batch), Err(KvsError::KeyNotFound)));
+    assert_eq!(store.get(b"key5")?, None);
+
+    // the batch's set cleared the TTL
+    thread::sleep(Duration::from_millis(400));
+    let check = |store: &E| -> Result<()> {
+        assert_eq!(store.get(b"key1")?, None);
+        assert_eq!(store.get(b"key2")?, Some(b"value4".to_vec()));
+        assert_eq!(store.get(b"key3")?, Some(b"value3".to_vec()));
+        assert_eq!(store.get(b"key4")?, None);
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&open()?)
+}
+
+engine_tests!(apply_batches);
+
+// A batch torn by a crash should be dropped as a whole, and batches should
+// survive compaction.
+#[test]
+fn recover_torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_threshold(temp_dir.path(), 4 * 1024)?;
+    for round in 0..100 {
+        let mut batch = WriteBatch::new();
+        for key_id in 0..10 {
+            batch.set(format!("key{}", key_id).into_bytes(), format!("value{}", round).into_bytes());
+        }
+        store.apply_batch(batch)?;
+    }
+    let mut batch = WriteBatch::new();
+    batch.set(b"key0".to_vec(), b"torn".to_vec()).set(b"key10".to_vec(), b"torn".to_vec());
+    store.apply_batch(batch)?;
+    drop(store);
+
+    let logs = log_files(temp_dir.path());
+    let last = logs.iter()
+        .max_by_key(|path| path.file_stem().unwrap().to_str().unwrap().parse::<u64>().unwrap())
+        .unwrap();
+    let f = OpenOptions::new().write(true).open(last)?;
+    f.set_len(f.metadata()?.len() - 1)?;
+    drop(f);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id).as_bytes())?, Some(b"value99".to_vec()));
+    }
+    assert_eq!(store.get(b"key10")?, None);
+    Ok(())
+}
@@ -0,0 +1,102 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsClient, KvsServer, Result, MAX_FRAME_SIZE};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Sends a command as a RESP array of bulk strings.
+fn send(stream: &mut TcpStream, args: &[&[u8]]) {
+    let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        buf.extend(format!("${}\r\n", arg.len()).into_bytes());
+        buf.extend_from_slice(arg);
+        buf.extend_from_slice(b"\r\n");
+    }
+    stream.write_all(&buf).unwrap();
+}
+
+// Reads one reply, returning it as it appeared on the wire.
+fn reply(reader: &mut BufReader<TcpStream>) -> String {
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    let len = line[1..].trim_end().parse::<i64>().unwrap_or(0);
+    match line.as_bytes()[0] {
+        b'$' if len >= 0 => {
+            let mut data = vec![0; len as usize + 2];
+            reader.read_exact(&mut data).unwrap();
+            line + &String::from_utf8(data).unwrap()
+        }
+        b'*' => (0..len).fold(line, |acc, _| acc + &reply(reader)),
+        _ => line,
+    }
+}
+
+// Redis commands should map onto the same engine the kvs protocol uses.
+#[test]
+fn resp_commands() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4200";
+    let resp_addr = "127.0.0.1:4201";
+    let server = KvsServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?)
+        .with_resp(resp_addr.parse().unwrap());
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.run(addr.parse().unwrap()));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut stream = TcpStream::connect(resp_addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut check = |args: &[&[u8]], expected: &str| {
+        send(&mut stream, args);
+        assert_eq!(reply(&mut reader), expected, "{:?}", args);
+    };
+    check(&[b"PING"], "+PONG\r\n");
+    check(&[b"ping", b"hi"], "$2\r\nhi\r\n");
+    check(&[b"SET", b"key1", b"value\r\n1"], "+OK\r\n");
+    check(&[b"GET", b"key1"], "$8\r\nvalue\r\n1\r\n");
+    check(&[b"GET", b"missing"], "$-1\r\n");
+    check(&[b"MSET", b"key2", b"value2", b"key3", b"value3"], "+OK\r\n");
+    check(&[b"MGET", b"key2", b"missing", b"key3"], "*3\r\n$6\r\nvalue2\r\n$-1\r\n$6\r\nvalue3\r\n");
+    check(&[b"EXISTS", b"key1", b"missing", b"key2"], ":2\r\n");
+    check(&[b"DEL", b"key1", b"missing"], ":1\r\n");
+    check(&[b"EXISTS", b"key1"], ":0\r\n");
+    check(&[b"SET", b"key4", b"value4", b"PX", b"100"], "+OK\r\n");
+    check(&[b"SET", b"key4", b"value4", b"EX", b"0"], "-ERR invalid expire time in 'set' command\r\n");
+    check(&[b"SET", b"key4", b"value4", b"NX", b"1"], "-ERR syntax error\r\n");
+    check(&[b"GET", b"key4"], "$6\r\nvalue4\r\n");
+    thread::sleep(Duration::from_millis(200));
+    check(&[b"GET", b"key4"], "$-1\r\n");
+    check(&[b"GET"], "-ERR wrong number of arguments for 'get' command\r\n");
+    check(&[b"FLUSHALL"], "-ERR unknown command 'FLUSHALL'\r\n");
+    send(&mut stream, &[b"INFO"]);
+    assert!(reply(&mut reader).contains("kvs_version:"));
+
+    // inline and pipelined commands
+    stream.write_all(b"PING\r\nGET key2\r\n")?;
+    assert_eq!(reply(&mut reader), "+PONG\r\n");
+    assert_eq!(reply(&mut reader), "$6\r\nvalue2\r\n");
+
+    // writes are visible through the kvs protocol
+    assert_eq!(KvsClient::connect(addr)?.get(b"key3")?, Some(b"value3".to_vec()));
+
+    // so is one whose arguments add up to more than a frame
+    let mut big = TcpStream::connect(resp_addr)?;
+    let mut big_reader = BufReader::new(big.try_clone()?);
+    let half = MAX_FRAME_SIZE / 2;
+    let mut command = format!("*2\r\n${}\r\n", half).into_bytes();
+    command.resize(command.len() + half, b'a');
+    command.extend(format!("\r\n${}\r\n", half + 1).into_bytes());
+    big.write_all(&command)?;
+    assert!(reply(&mut big_reader).starts_with("-ERR Protocol error"));
+
+    // a malformed command is answered with an error and the connection closed
+    stream.write_all(b"*1\r\n+PING\r\n")?;
+    assert!(reply(&mut reader).starts_with("-ERR Protocol error"));
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest)?;
+    assert!(rest.is_empty());
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
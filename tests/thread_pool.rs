@@ -0,0 +1,67 @@
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::Result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::time::Duration;
+
+// Runs `jobs` jobs on the pool and waits for all of them to finish.
+fn spawn_counter<P: ThreadPool>(pool: P, jobs: usize) -> Result<()> {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let (tx, rx) = mpsc::channel();
+    for _ in 0..jobs {
+        let counter = Arc::clone(&counter);
+        let tx = tx.clone();
+        pool.spawn(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            tx.send(()).unwrap();
+        });
+    }
+    for _ in 0..jobs {
+        rx.recv_timeout(Duration::from_secs(5))
+            .expect("job did not finish");
+    }
+    assert_eq!(counter.load(Ordering::SeqCst), jobs);
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(NaiveThreadPool::new(4)?, 100)
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(SharedQueueThreadPool::new(4)?, 100)
+}
+
+#[test]
+fn rayon_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(RayonThreadPool::new(4)?, 100)
+}
+
+// Jobs keep running after every thread in the pool has panicked once.
+#[test]
+fn shared_queue_thread_pool_panic_task() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(4)?;
+    for _ in 0..4 {
+        pool.spawn(|| panic!("expected panic in thread pool job"));
+    }
+    spawn_counter(pool, 100)
+}
+
+#[test]
+fn rayon_thread_pool_panic_task() -> Result<()> {
+    let pool = RayonThreadPool::new(4)?;
+    for _ in 0..4 {
+        pool.spawn(|| panic!("expected panic in thread pool job"));
+    }
+    spawn_counter(pool, 100)
+}
+
+// A pool without threads could never run the jobs it accepts.
+#[test]
+fn thread_pool_rejects_zero_threads() {
+    assert!(SharedQueueThreadPool::new(0).is_err());
+    assert!(RayonThreadPool::new(0).is_err());
+}
@@ -0,0 +1,79 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{tls, KvStore, KvsClient, KvsServer, Result};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa, KeyPair};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// A self-signed CA, written to `{name}.pem`.
+fn ca(dir: &Path, name: &str) -> (Certificate, KeyPair, PathBuf) {
+    let key = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let cert = params.self_signed(&key).unwrap();
+    let path = dir.join(format!("{}.pem", name));
+    fs::write(&path, cert.pem()).unwrap();
+    (cert, key, path)
+}
+
+// A certificate for `san` signed by a CA, written to `{name}.pem` with its
+// key in `{name}.key`.
+fn issue(dir: &Path, name: &str, san: &str, ca: &(Certificate, KeyPair, PathBuf)) -> (PathBuf, PathBuf) {
+    let key = KeyPair::generate().unwrap();
+    let cert = CertificateParams::new(vec![san.to_string()])
+        .unwrap()
+        .signed_by(&key, &ca.0, &ca.1)
+        .unwrap();
+    let cert_path = dir.join(format!("{}.pem", name));
+    let key_path = dir.join(format!("{}.key", name));
+    fs::write(&cert_path, cert.pem()).unwrap();
+    fs::write(&key_path, key.serialize_pem()).unwrap();
+    (cert_path, key_path)
+}
+
+// With mutual TLS, only clients that trust the server's CA and present a
+// certificate from the client CA should get through.
+#[test]
+fn mutual_tls() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let certs = TempDir::new().expect("unable to create temporary certificate directory");
+    let dir = certs.path();
+    let server_ca = ca(dir, "server-ca");
+    let client_ca = ca(dir, "client-ca");
+    let other_ca = ca(dir, "other-ca");
+    let (server_cert, server_key) = issue(dir, "server", "127.0.0.1", &server_ca);
+    let (client_cert, client_key) = issue(dir, "client", "client", &client_ca);
+    let (rogue_cert, rogue_key) = issue(dir, "rogue", "client", &other_ca);
+
+    let addr = "127.0.0.1:4400";
+    let server = KvsServer::new(KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(2)?)
+        .with_tls(tls::server_config(&server_cert, &server_key, Some(&client_ca.2))?);
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.run(addr.parse().unwrap()));
+    thread::sleep(Duration::from_millis(500));
+
+    let config = tls::client_config(&server_ca.2, Some((&client_cert, &client_key)))?;
+    let mut client = KvsClient::connect_tls(addr, "127.0.0.1", config.clone())?;
+    client.set(b"key1".to_vec(), b"value1".to_vec())?;
+    assert_eq!(client.get(b"key1")?, Some(b"value1".to_vec()));
+    drop(client);
+
+    // the server's certificate is not valid for another name
+    assert!(KvsClient::connect_tls(addr, "example.com", config).is_err());
+    // the client does not trust the server's CA
+    let config = tls::client_config(&other_ca.2, Some((&client_cert, &client_key)))?;
+    assert!(KvsClient::connect_tls(addr, "127.0.0.1", config).is_err());
+    // the server does not trust the client's CA
+    let config = tls::client_config(&server_ca.2, Some((&rogue_cert, &rogue_key)))?;
+    assert!(KvsClient::connect_tls(addr, "127.0.0.1", config).is_err());
+    // no client certificate at all
+    let config = tls::client_config(&server_ca.2, None)?;
+    assert!(KvsClient::connect_tls(addr, "127.0.0.1", config).is_err());
+    // no TLS at all
+    assert!(KvsClient::connect(addr).is_err());
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}